@@ -19,34 +19,70 @@
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::{parser::Literal, error, Result};
-use std::fmt::{self, Formatter, Display};
+use std::{fmt::{self, Formatter, Display}, rc::Rc};
+
+// Where the value of a variable is found, resolved by the compiler.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Slot {
+    Local(u16),   // (slot)
+    Upvalue(u16), // (upvalue_idx)
+    Global(u16),  // (sym_id)
+}
+
+// A variable captured by a function when it is created, from the frame creating it.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Capture {
+    Local(u16),   // (slot)
+    Upvalue(u16), // (upvalue_idx)
+}
+impl Slot {
+    pub fn load(self) -> OpCode {
+        match self {
+            Self::Local(slot) => OpCode::LoadLocal(slot),
+            Self::Upvalue(idx) => OpCode::LoadUpvalue(idx),
+            Self::Global(id) => OpCode::LoadGlobal(id),
+        }
+    }
+}
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum OpCode {
     LoadConst(u16),        // (const_id)
-    LoadSym(u16),          // (sym_id)
+    LoadGlobal(u16),       // (sym_id)
+    LoadLocal(u16),        // (slot)
+    LoadUpvalue(u16),      // (upvalue_idx)
     Call(u16),             // (argc)
+    TailCall(u16),         // (argc)
     Builtin(u8, u8),       // (builtin_id, argc)
-    Def(u16, u16),         // (sym_id, instructions_length)
+    Def(u16),              // (sym_id)
+    DefLocal(u16),         // (slot)
     Lambda(u16),           // (chunk_id)
-    Constructor(u16, u16), // (constr_idx, valc)
-    Tuple(u16, u16),       // (instr_amount, amount)
+    Constructor(u16),      // (constr_idx)
+    Tuple(u16),            // (amount)
     Match(u16),            // (match_idx)
+    Jump(u16),             // (offset)
     Panic(u16, u16),       // (file_sym, line_sym)
+    Eval,
 }
 impl Display for OpCode {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Self::LoadConst(i)      => write!(f, "CONST   {:04X}", i),
-            Self::LoadSym(i)        => write!(f, "LOAD    {:04X}", i),
+            Self::LoadGlobal(i)     => write!(f, "GLOBAL  {:04X}", i),
+            Self::LoadLocal(i)      => write!(f, "LOCAL   {:04X}", i),
+            Self::LoadUpvalue(i)    => write!(f, "UPVAL   {:04X}", i),
             Self::Call(i)           => write!(f, "CALL    {:04X}", i),
+            Self::TailCall(i)       => write!(f, "TCALL   {:04X}", i),
             Self::Builtin(i, a)     => write!(f, "BUILTIN {:02X}   {:02X}", i, a),
-            Self::Def(i, l)         => write!(f, "DEF     {:04X} {:04X}", i, l),
+            Self::Def(i)            => write!(f, "DEF     {:04X}", i),
+            Self::DefLocal(i)       => write!(f, "DEFLOC  {:04X}", i),
             Self::Lambda(i)         => write!(f, "FUN     {:04X}", i),
-            Self::Constructor(i, v) => write!(f, "CONSTR  {:04X} {:04X}", i, v),
-            Self::Tuple(i, a)       => write!(f, "TUP     {:04X} {:04X}", i, a),
+            Self::Constructor(i)    => write!(f, "CONSTR  {:04X}", i),
+            Self::Tuple(a)          => write!(f, "TUP     {:04X}", a),
             Self::Match(i)          => write!(f, "MATCH   {:04X}", i),
+            Self::Jump(o)           => write!(f, "JUMP    {:04X}", o),
             Self::Panic(fi, l)      => write!(f, "PANIC   {:04X} {:04X}", fi, l),
+            Self::Eval              => write!(f, "EVAL"),
         }
     }
 }
@@ -55,18 +91,24 @@ impl OpCode {
         *ptr += 1;
         match bytes[*ptr - 1] {
             0 => Ok(Self::LoadConst(len(ptr, bytes)?)),
-            1 => Ok(Self::LoadSym(len(ptr, bytes)?)),
+            1 => Ok(Self::LoadGlobal(len(ptr, bytes)?)),
             2 => Ok(Self::Call(len(ptr, bytes)?)),
             3 => {
                 *ptr += 2;
                 Ok(Self::Builtin(bytes[*ptr - 2], bytes[*ptr - 1])) 
             }
-            4 => Ok(Self::Def(len(ptr, bytes)?, len(ptr, bytes)?)),
+            4 => Ok(Self::Def(len(ptr, bytes)?)),
             5 => Ok(Self::Lambda(len(ptr, bytes)?)),
-            6 => Ok(Self::Constructor(len(ptr, bytes)?, len(ptr, bytes)?)),
-            7 => Ok(Self::Tuple(len(ptr, bytes)?, len(ptr, bytes)?)),
+            6 => Ok(Self::Constructor(len(ptr, bytes)?)),
+            7 => Ok(Self::Tuple(len(ptr, bytes)?)),
             8 => Ok(Self::Match(len(ptr, bytes)?)), 
             9 => Ok(Self::Panic(len(ptr, bytes)?, len(ptr, bytes)?)),
+            10 => Ok(Self::Eval),
+            11 => Ok(Self::LoadLocal(len(ptr, bytes)?)),
+            12 => Ok(Self::LoadUpvalue(len(ptr, bytes)?)),
+            13 => Ok(Self::DefLocal(len(ptr, bytes)?)),
+            14 => Ok(Self::Jump(len(ptr, bytes)?)),
+            15 => Ok(Self::TailCall(len(ptr, bytes)?)),
             x => error!(=> "Unrecognised op code: {}.", x),
         }
     }
@@ -77,7 +119,7 @@ impl OpCode {
                 to_ret.extend(&id.to_be_bytes());
                 to_ret
             }
-            Self::LoadSym(id) => {
+            Self::LoadGlobal(id) => {
                 let mut to_ret = vec![1];
                 to_ret.extend(&id.to_be_bytes());
                 to_ret
@@ -88,10 +130,9 @@ impl OpCode {
                 to_ret
             }
             Self::Builtin(idx, argc) => vec![3, *idx, *argc],
-            Self::Def(id, len) => {
+            Self::Def(id) => {
                 let mut to_ret = vec![4];
                 to_ret.extend(&id.to_be_bytes());
-                to_ret.extend(&len.to_be_bytes());
                 to_ret
             }
             Self::Lambda(id) => {
@@ -99,16 +140,14 @@ impl OpCode {
                 to_ret.extend(&id.to_be_bytes());
                 to_ret
             }
-            Self::Constructor(idx, contained) => {
+            Self::Constructor(idx) => {
                 let mut to_ret = vec![6];
                 to_ret.extend(&idx.to_be_bytes());
-                to_ret.extend(&contained.to_be_bytes());
                 to_ret
             }
-            Self::Tuple(amount, vals) => {
+            Self::Tuple(amount) => {
                 let mut to_ret = vec![7];
                 to_ret.extend(&amount.to_be_bytes());
-                to_ret.extend(&vals.to_be_bytes());
                 to_ret
             }
             Self::Match(idx) => {
@@ -122,34 +161,76 @@ impl OpCode {
                 to_ret.extend(&line.to_be_bytes());
                 to_ret
             }
+            Self::Eval => vec![10],
+            Self::LoadLocal(slot) => {
+                let mut to_ret = vec![11];
+                to_ret.extend(&slot.to_be_bytes());
+                to_ret
+            }
+            Self::LoadUpvalue(idx) => {
+                let mut to_ret = vec![12];
+                to_ret.extend(&idx.to_be_bytes());
+                to_ret
+            }
+            Self::DefLocal(slot) => {
+                let mut to_ret = vec![13];
+                to_ret.extend(&slot.to_be_bytes());
+                to_ret
+            }
+            Self::Jump(offset) => {
+                let mut to_ret = vec![14];
+                to_ret.extend(&offset.to_be_bytes());
+                to_ret
+            }
+            Self::TailCall(argc) => {
+                let mut to_ret = vec![15];
+                to_ret.extend(&argc.to_be_bytes());
+                to_ret
+            }
         }
     }
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Chunk {
-    pub instructions: Vec<OpCode>,
-    pub reference: Vec<u16>,
+    pub instructions: Rc<[OpCode]>, // Shared by the copies of the bytecode, as chunks are never changed once compiled.
+    pub arity: u16,
+    pub slots: u16, // The size of the frame, arguments included.
+    pub captures: Rc<[Capture]>, // The free variables of the function.
 }
 
 #[derive(PartialEq, Clone, Debug)]
-pub enum BytecodePattern {
-    Var(u16), // (sym_idx)
-    Constr(u16, Vec<u16>), // (constr_id, [pat_idx])
-    Tuple(Vec<u16>), // ([pat_idx])
+pub enum Test {
+    Constr(u16), // (constr_id)
+    Tuple(u16), // (length)
     Literal(u16), // (const_id)
-    Any, // `_` variable 
+    Range(u16, u16), // (start_const_id, end_const_id)
+    Prefix(u16), // (const_id)
+    Pin(Slot), // (variable)
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Decision {
+    Fail,
+    Leaf(u16, Vec<(u16, u16)>), // (arm_idx, [(slot, occurrence)])
+    Switch(u16, Vec<(Test, u16, Decision)>, Box<Decision>), // (occurrence, [(test, first_child_occurrence, subtree)], default)
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Match {
+    pub occurrences: u16,
+    pub tree: Decision,
+    pub arms: Vec<u16>, // The offset of each arm from the instruction following the `Match` one, each but the last arm ending with a `Jump` after the last one.
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Bytecode {
     pub types: Vec<(String, u16, u16)>,
     pub chunks: Vec<Chunk>,
-    pub matches: Vec<Vec<(u16, Vec<OpCode>)>>,
+    pub matches: Vec<Rc<Match>>,
     pub symbols: Vec<String>,
     pub constants: Vec<Literal>,
     pub instructions: Vec<OpCode>,
-    pub patterns: Vec<BytecodePattern>,
     pub constructors: Vec<(u8, u16)>,
 }
 
@@ -163,7 +244,6 @@ impl Bytecode {
             instructions: vec![],
             constructors: vec![],
             matches: vec![],
-            patterns: vec![],
         }
     }
     // All numbers here are big endian
@@ -204,17 +284,26 @@ impl Bytecode {
             let chunks_length = len(&mut ptr, bytes)?;
             println!("Chunks length.");
             let chunks = (0..chunks_length).map(|_| {
-                let ref_len = len(&mut ptr, bytes)?;
-                let reference = (0..ref_len).map(|_| {
-                    len(&mut ptr, bytes)
-                }).collect::<Result<Vec<u16>>>()?;
+                let arity = len(&mut ptr, bytes)?;
+                let slots = len(&mut ptr, bytes)?;
+                let captures_len = len(&mut ptr, bytes)?;
+                let captures = (0..captures_len).map(|_| {
+                    ptr += 1;
+                    match bytes[ptr - 1] {
+                        0 => Ok(Capture::Local(len(&mut ptr, bytes)?)),
+                        1 => Ok(Capture::Upvalue(len(&mut ptr, bytes)?)),
+                        x => error!(=> "Unrecognised capture: {}.", x),
+                    }
+                }).collect::<Result<Rc<[Capture]>>>()?;
                 let instr_len = len(&mut ptr, bytes)? as usize;
                 let instructions = (0..instr_len).map(|_| {
                     OpCode::deserialize(&mut ptr, bytes)
-                }).collect::<Result<Vec<OpCode>>>()?;
+                }).collect::<Result<Rc<[OpCode]>>>()?;
                 Ok(Chunk {
                     instructions,
-                    reference
+                    arity,
+                    slots,
+                    captures,
                 })
             }).collect::<Result<Vec<Chunk>>>()?;
             println!("Chunks.");
@@ -236,42 +325,19 @@ impl Bytecode {
             }).collect::<Result<Vec<_>>>()?;
             println!("Types.");
 
-            let patterns_length = len(&mut ptr, bytes)?;
-            println!("Patterns_length.");
-            let patterns = (0..patterns_length).map(|_| {
-                ptr += 1;
-                match bytes[ptr - 1] {
-                    0 => Ok(BytecodePattern::Var(len(&mut ptr, bytes)?)),
-                    1 => {
-                        let id = len(&mut ptr, bytes)?;
-                        let length = len(&mut ptr, bytes)?;
-                        let pats = (0..length).map(|_| len(&mut ptr, bytes)).collect::<Result<Vec<u16>>>()?;
-                        Ok(BytecodePattern::Constr(id, pats))
-                    }
-                    2 => {
-                        let length = len(&mut ptr, bytes)?;
-                        let pats = (0..length).map(|_| len(&mut ptr, bytes)).collect::<Result<Vec<u16>>>()?;
-                        Ok(BytecodePattern::Tuple(pats))
-                    }
-                    3 => Ok(BytecodePattern::Literal(len(&mut ptr, bytes)?)),
-                    4 => Ok(BytecodePattern::Any),
-                    _ => error!(=> "Invalid pattern."),
-                }
-            }).collect::<Result<Vec<BytecodePattern>>>()?;
-            println!("Patterns.");
             let matches_length = len(&mut ptr, bytes)?;
             println!("Matches length.");
             let matches = (0..matches_length).map(|_| {
-                let match_length = len(&mut ptr, bytes)?;
-                Ok((0..match_length).map(|_| {
-                    let idx = len(&mut ptr, bytes)?;
-                    let instrs_len = len(&mut ptr, bytes)?;
-                    let instrs = (0..instrs_len).map(|_| {
-                        OpCode::deserialize(&mut ptr, bytes)
-                    }).collect::<Result<Vec<OpCode>>>()?;
-                    Ok((idx, instrs))
-                }).collect::<Result<Vec<(u16, Vec<OpCode>)>>>()?)
-            }).collect::<Result<Vec<Vec<(u16, Vec<OpCode>)>>>>()?;
+                let occurrences = len(&mut ptr, bytes)?;
+                let arms_length = len(&mut ptr, bytes)?;
+                let arms = (0..arms_length).map(|_| len(&mut ptr, bytes)).collect::<Result<Vec<u16>>>()?;
+                let tree = decision(&mut ptr, bytes)?;
+                Ok(Rc::new(Match {
+                    occurrences,
+                    tree,
+                    arms,
+                }))
+            }).collect::<Result<Vec<Rc<Match>>>>()?;
             println!("Matches.");
 
             Ok(Bytecode {
@@ -281,7 +347,6 @@ impl Bytecode {
                 symbols,
                 constants,
                 instructions,
-                patterns,
                 constructors
             })
         }
@@ -329,9 +394,16 @@ impl Bytecode {
         // Chunks
         to_ret.extend(&(self.chunks.len() as u16).to_be_bytes());
         self.chunks.iter().for_each(|chunk| {
-            to_ret.extend(&(chunk.reference.len() as u16).to_be_bytes());
-            chunk.reference.iter().for_each(|link| {
-                to_ret.extend(&link.to_be_bytes());
+            to_ret.extend(&chunk.arity.to_be_bytes());
+            to_ret.extend(&chunk.slots.to_be_bytes());
+            to_ret.extend(&(chunk.captures.len() as u16).to_be_bytes());
+            chunk.captures.iter().for_each(|capture| {
+                let (tag, operand) = match capture {
+                    Capture::Local(slot) => (0, slot),
+                    Capture::Upvalue(idx) => (1, idx),
+                };
+                to_ret.push(tag);
+                to_ret.extend(&operand.to_be_bytes());
             });
 
             let serialized = chunk.instructions.iter().map(|instr| {
@@ -357,57 +429,104 @@ impl Bytecode {
             to_ret.push(0);
         });
 
-        // Patterns
-        to_ret.extend(&(self.patterns.len() as u16).to_be_bytes());
-        to_ret.extend(self.patterns.iter().map(|p| {
-            match p {
-                BytecodePattern::Var(idx) => {
-                    let mut to_ret = vec![0];
-                    to_ret.extend(&idx.to_be_bytes());
-                    to_ret
-                }
-                BytecodePattern::Constr(id, pats) => {
-                    let mut to_ret = vec![1];
-                    to_ret.extend(&id.to_be_bytes());
-                    to_ret.extend(&(pats.len() as u16).to_be_bytes());
-                    to_ret.extend(pats.into_iter().map(|p| {
-                        p.to_be_bytes().to_vec()
-                    }).flatten());
-                    to_ret
-                }
-                BytecodePattern::Tuple(pats) =>  {
-                    let mut to_ret = vec![2];
-                    to_ret.extend(&(pats.len() as u16).to_be_bytes());
-                    to_ret.extend(pats.into_iter().map(|p| {
-                        p.to_be_bytes().to_vec()
-                    }).flatten());
-                    to_ret
-                }
-                BytecodePattern::Literal(idx) => {
-                    let mut to_ret = vec![3];
-                    to_ret.extend(&idx.to_be_bytes());
-                    to_ret
-                }
-                BytecodePattern::Any => vec![4],
-            }
-        }).flatten());
-
         // Matches
         to_ret.extend(&(self.matches.len() as u16).to_be_bytes());
-        to_ret.extend(self.matches.iter().map(|patterns| {
-            let mut to_ret = (patterns.len() as u16).to_be_bytes().to_vec();
-            to_ret.extend(patterns.into_iter().map(|(idx, instrs)| {
-                let mut to_ret = idx.to_be_bytes().to_vec();
-                to_ret.extend(&(instrs.len() as u16).to_be_bytes());
-                to_ret.extend(instrs.into_iter().map(|instr| instr.serialize()).flatten());
-                to_ret
-            }).flatten());
-            to_ret
-        }).flatten());
+        self.matches.iter().for_each(|m| {
+            to_ret.extend(&m.occurrences.to_be_bytes());
+            to_ret.extend(&(m.arms.len() as u16).to_be_bytes());
+            m.arms.iter().for_each(|offset| to_ret.extend(&offset.to_be_bytes()));
+            to_ret.extend(m.tree.serialize());
+        });
         to_ret
     }
 }
 
+impl Test {
+    pub fn serialize(&self) -> Vec<u8> {
+        let (tag, operands) = match self {
+            Self::Constr(id) => (0, vec![*id]),
+            Self::Tuple(length) => (1, vec![*length]),
+            Self::Literal(id) => (2, vec![*id]),
+            Self::Range(start, end) => (3, vec![*start, *end]),
+            Self::Prefix(id) => (4, vec![*id]),
+            Self::Pin(Slot::Local(slot)) => (5, vec![0, *slot]),
+            Self::Pin(Slot::Upvalue(idx)) => (5, vec![1, *idx]),
+            Self::Pin(Slot::Global(id)) => (5, vec![2, *id]),
+        };
+        let mut to_ret = vec![tag];
+        to_ret.extend(operands.into_iter().flat_map(|o| o.to_be_bytes().to_vec()));
+        to_ret
+    }
+    pub fn deserialize(ptr: &mut usize, bytes: &[u8]) -> Result<Self> {
+        *ptr += 1;
+        match bytes[*ptr - 1] {
+            0 => Ok(Self::Constr(len(ptr, bytes)?)),
+            1 => Ok(Self::Tuple(len(ptr, bytes)?)),
+            2 => Ok(Self::Literal(len(ptr, bytes)?)),
+            3 => Ok(Self::Range(len(ptr, bytes)?, len(ptr, bytes)?)),
+            4 => Ok(Self::Prefix(len(ptr, bytes)?)),
+            5 => Ok(Self::Pin(match len(ptr, bytes)? {
+                0 => Slot::Local(len(ptr, bytes)?),
+                1 => Slot::Upvalue(len(ptr, bytes)?),
+                2 => Slot::Global(len(ptr, bytes)?),
+                x => return error!(=> "Unrecognised variable kind: {}.", x),
+            })),
+            x => error!(=> "Unrecognised test: {}.", x),
+        }
+    }
+}
+impl Decision {
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Self::Fail => vec![0],
+            Self::Leaf(arm, bindings) => {
+                let mut to_ret = vec![1];
+                to_ret.extend(&arm.to_be_bytes());
+                to_ret.extend(&(bindings.len() as u16).to_be_bytes());
+                bindings.iter().for_each(|(sym, occurrence)| {
+                    to_ret.extend(&sym.to_be_bytes());
+                    to_ret.extend(&occurrence.to_be_bytes());
+                });
+                to_ret
+            }
+            Self::Switch(occurrence, cases, default) => {
+                let mut to_ret = vec![2];
+                to_ret.extend(&occurrence.to_be_bytes());
+                to_ret.extend(&(cases.len() as u16).to_be_bytes());
+                cases.iter().for_each(|(test, child, subtree)| {
+                    to_ret.extend(test.serialize());
+                    to_ret.extend(&child.to_be_bytes());
+                    to_ret.extend(subtree.serialize());
+                });
+                to_ret.extend(default.serialize());
+                to_ret
+            }
+        }
+    }
+}
+fn decision(ptr: &mut usize, bytes: &[u8]) -> Result<Decision> {
+    *ptr += 1;
+    match bytes[*ptr - 1] {
+        0 => Ok(Decision::Fail),
+        1 => {
+            let arm = len(ptr, bytes)?;
+            let length = len(ptr, bytes)?;
+            let bindings = (0..length).map(|_| Ok((len(ptr, bytes)?, len(ptr, bytes)?))).collect::<Result<Vec<(u16, u16)>>>()?;
+            Ok(Decision::Leaf(arm, bindings))
+        }
+        2 => {
+            let occurrence = len(ptr, bytes)?;
+            let length = len(ptr, bytes)?;
+            let cases = (0..length).map(|_| {
+                let test = Test::deserialize(ptr, bytes)?;
+                let child = len(ptr, bytes)?;
+                Ok((test, child, decision(ptr, bytes)?))
+            }).collect::<Result<Vec<(Test, u16, Decision)>>>()?;
+            Ok(Decision::Switch(occurrence, cases, Box::new(decision(ptr, bytes)?)))
+        }
+        x => error!(=> "Invalid decision node: {}.", x),
+    }
+}
 fn string(ptr: &mut usize, bytes: &[u8]) -> Result<String> {
     let mut to_ret = String::new();
     while *ptr < bytes.len() && bytes[*ptr] != 0 {
@@ -18,12 +18,12 @@
  *  You should have received a copy of the GNU General Public License
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use rustyline::{error::ReadlineError, Editor};
-use std::{rc::Rc, time::Instant, path::Path, fs, io::Write};
-use crate::{Result, print_err, error, lexer::{Lexer, Token}, parser::{Parser, Expr}, bytecode::Bytecode, compiler::{Compiler, Macro}, vm::{VM, Value}};
+use std::{rc::Rc, time::{Duration, Instant}, path::Path, fs, io::Write};
+use crate::{Result, print_err, print_warning, error, lexer::{Lexer, Token}, parser::{Parser, Expr}, bytecode::Bytecode, compiler::{relative, Compiler, Macro}, gc::Heap, modules::Namespace, vm::{Limits, VM, Value}};
 
-fn repl(dbg_level: u8, lib: String) -> Result<()> {
+fn repl(dbg_level: u8, lib: String, paths: Vec<String>, prelude: Option<String>, limits: Limits, optimize: bool) -> Result<()> {
     println!(
         ";; Orion REPL v{}.\n
 ;; Copyright (C) 2021  Wafelack <wafelack@protonmail.com>
@@ -32,14 +32,14 @@ fn repl(dbg_level: u8, lib: String) -> Result<()> {
 ;; under certain conditions.",
 env!("CARGO_PKG_VERSION")
 );
-    let mut ctx = vec![];
+    let mut globals = vec![];
     let mut symbols = vec![];
     let mut bytecode = Bytecode::new();
     let mut constructors = vec![];
-    let mut sym_ref = vec![];
-    let mut saves = vec![];
     let mut macros = vec![];
-    let mut vm = VM::new(Bytecode::new(), vec![]);
+    let mut namespaces = (vec![], Namespace::default());
+    let mut heap = Heap::default();
+    let mut vm = VM::new(Bytecode::new());
 
     let mut rl = Editor::<()>::new();
     let mut i = 0;
@@ -54,6 +54,12 @@ env!("CARGO_PKG_VERSION")
                 if line == "(quit)" {
                     return Ok(());
                 }
+                if line.trim() == ":gc" {
+                    let stats = heap.collect(globals.iter().flatten().cloned().collect(), vec![]);
+                    println!("{} Freed {} environments, {} in total.", STAR, stats.collected, heap.collected);
+                    println!("{} {} environments and {} values are live.", STAR, stats.environments, stats.values);
+                    continue;
+                }
                 let start = Instant::now();
                 let tokens = match Lexer::new(line, "REPL").line(i).proc_tokens() {
                     Ok(t) => t,
@@ -70,8 +76,8 @@ env!("CARGO_PKG_VERSION")
                        continue;
                    }
                 };
-                let (new_bytecode, new_syms, new_constructors, new_macros) = match (match Compiler::new(expressions, "REPL", bytecode.clone(), constructors.clone(),  i > 1, lib.clone(), true, macros.clone()) {
-                    Ok(c) => c,
+                let mut compiler = match Compiler::new(expressions, "REPL", bytecode.clone(), constructors.clone(),  i > 1, lib.clone(), true, macros.clone()) {
+                    Ok(c) => c.with_namespaces(namespaces.clone()).with_globals(globals.clone()).with_paths(paths.clone()).with_prelude(prelude.clone()).with_limits(limits).with_optimizations(optimize),
                     Err(e) => {
                         if i == 1 {
                             i = 0;
@@ -79,7 +85,10 @@ env!("CARGO_PKG_VERSION")
                         print_err(e);
                         continue;
                     }
-                }).compile(symbols.clone()) {
+                };
+                let compiled = compiler.compile(symbols.clone());
+                compiler.warnings.drain(..).for_each(print_warning);
+                let (new_bytecode, new_syms, new_constructors, new_macros) = match compiled {
                     Ok(b) => b,
                     Err(e) => {
                         if i == 1 {
@@ -93,21 +102,28 @@ env!("CARGO_PKG_VERSION")
                 symbols = new_syms;
                 constructors = new_constructors;
                 macros = new_macros;
+                namespaces = compiler.namespaces();
                 let elapsed = start.elapsed();
                 if dbg_level > 1 {
                     println!("{} Compiled in {}ms.", STAR, elapsed.as_millis());
                 }
-                vm = VM::<16000>::new(bytecode.clone(), saves.clone());
-                let (new_ctx, new_ref, new_saves) = match vm.eval(sym_ref.clone(), ctx.clone(), dbg_level > 2) {
+                vm = VM::<STACK_SIZE>::new(bytecode.clone()).with_limits(limits);
+                vm.macros = macros.clone();
+                vm.namespaces = namespaces.clone();
+                vm.heap = std::mem::take(&mut heap);
+                let result = vm.eval(globals.clone(), dbg_level > 2);
+                heap = std::mem::take(&mut vm.heap);
+                globals = match result {
                     Ok(v) => v,
                     Err(e) => {
                         print_err(e);
                         continue;
                     }
                 };
-                ctx = new_ctx;
-                sym_ref = new_ref;
-                saves = new_saves;
+                // Keep the chunks, symbols and constructors `eval` may have added.
+                bytecode = vm.input.clone();
+                constructors = bytecode.constructors.iter().map(|(_, sym)| bytecode.symbols[*sym as usize].clone()).collect();
+                symbols.extend(bytecode.symbols[symbols.len()..].iter().map(|name| (name.to_string(), true)));
                 let top = &vm.stack.iter().nth(match vm.stack.len() as isize - 1 {
                     x if x < 0 => 0,
                     x => x as usize,
@@ -133,7 +149,37 @@ env!("CARGO_PKG_VERSION")
     }
 }
 
+// Prints a file and, the first time it is met, the tree of the files it loads.
+fn print_dependencies(graph: &[(String, Vec<String>)], file: &str, prefix: &str, shown: &mut Vec<String>) {
+    let name = relative(file);
+    if shown.iter().any(|f| f == file) {
+        println!("{} (*)", name);
+        return;
+    }
+    println!("{}", name);
+    shown.push(file.to_string());
+    let dependencies = graph.iter().find(|(f, _)| f == file).map_or(&[][..], |(_, d)| d.as_slice());
+    for (i, dependency) in dependencies.iter().enumerate() {
+        let last = i + 1 == dependencies.len();
+        print!("{}{}", prefix, if last { "└── " } else { "├── " });
+        print_dependencies(graph, dependency, &format!("{}{}", prefix, if last { "    " } else { "│   " }), shown);
+    }
+}
+
 const STAR: &str = "\x1b[0;32m*\x1b[0m";
+// The default bound of the operand stack and of the call depth.
+const STACK_SIZE: usize = 16000;
+
+// The value of a numeric option, if given.
+fn numeric<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match matches.value_of(name) {
+        Some(value) => match value.parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => error!(=> "Invalid value for --{}: {}.", name, value),
+        },
+        None => Ok(None),
+    }
+}
 
 macro_rules! get_app {
     ($name:literal, $version:expr) => {
@@ -159,7 +205,28 @@ There is NO WARRANTY, to the extent permitted by law.", $version).as_str())
                  .short("l")
                  .long("lib")
                  .takes_value(true)
+                 .global(true)
                  .help("The library folder to use instead of $ORION_LIB."))
+            .arg(Arg::with_name("no-prelude")
+                 .long("no-prelude")
+                 .conflicts_with("prelude")
+                 .global(true)
+                 .help("Do not load the prelude."))
+            .arg(Arg::with_name("prelude")
+                 .long("prelude")
+                 .takes_value(true)
+                 .value_name("FILE")
+                 .global(true)
+                 .help("Load FILE instead of the prelude."))
+            .arg(Arg::with_name("path")
+                 .short("L")
+                 .long("path")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .value_name("DIR")
+                 .global(true)
+                 .help("Add DIR to the directories searched for loaded files, before $ORION_PATH."))
             .arg(Arg::with_name("compile-only")
                  .short("c")
                  .long("compile-only")
@@ -170,24 +237,83 @@ There is NO WARRANTY, to the extent permitted by law.", $version).as_str())
                  .takes_value(true)
                  .value_name("FILE")
                  .help("Place the output into FILE."))
+            .arg(Arg::with_name("optimize")
+                 .short("O")
+                 .long("optimize")
+                 .global(true)
+                 .help("Fold constants, inline the functions wrapping builtins, simplify begin blocks and matches on known values, and drop the unused definitions of compiled files."))
             .arg(Arg::with_name("debug-level")
                  .short("d")
                  .long("debug")
                  .value_name("LEVEL")
                  .takes_value(true)
                  .help("Set the debug level. Defaults to 0."))
+            .arg(Arg::with_name("max-stack")
+                 .long("max-stack")
+                 .takes_value(true)
+                 .value_name("N")
+                 .global(true)
+                 .help("Stop when more than N values are on the stack. Defaults to 16000."))
+            .arg(Arg::with_name("max-depth")
+                 .long("max-depth")
+                 .takes_value(true)
+                 .value_name("N")
+                 .global(true)
+                 .help("Stop when more than N functions are being run. Defaults to 16000."))
+            .arg(Arg::with_name("fuel")
+                 .long("fuel")
+                 .takes_value(true)
+                 .value_name("N")
+                 .global(true)
+                 .help("Stop after running N instructions."))
+            .arg(Arg::with_name("timeout")
+                 .long("timeout")
+                 .takes_value(true)
+                 .value_name("MS")
+                 .global(true)
+                 .help("Stop after running for MS milliseconds."))
+            .arg(Arg::with_name("max-memory")
+                 .long("max-memory")
+                 .takes_value(true)
+                 .value_name("BYTES")
+                 .global(true)
+                 .help("Stop after allocating about BYTES bytes, including the freed ones."))
+            .subcommand(SubCommand::with_name("deps")
+                        .about("Print the files loaded by FILE, as a tree.")
+                        .arg(Arg::with_name("file")
+                             .index(1)
+                             .required(true)
+                             .value_name("FILE")
+                             .help("The source file whose dependencies to print.")))
     }
 }
 use std::env;
 pub fn cli() -> Result<()> {
     let matches = get_app!("Orion", env!("CARGO_PKG_VERSION")).get_matches();
+    // Without a library folder, the standard library embedded in the binary is used.
     let lib = match matches.value_of("lib") {
         Some(l) => l.to_string(),
-        None => match env::var("ORION_LIB") {
-            Ok(v) => v.to_string(),
-            Err(_) => return error!(=> "No such environment variable: ORION_LIB."),
+        None => env::var("ORION_LIB").unwrap_or_default(),
+    };
+    let prelude = if matches.is_present("no-prelude") {
+        None
+    } else {
+        match matches.value_of("prelude") {
+            // Made absolute, as loads are resolved from the directory of the loading file.
+            Some(f) => match fs::canonicalize(f) {
+                Ok(p) => Some(p.to_string_lossy().to_string()),
+                Err(e) => return error!(=> "Failed to read file: {}: {}.", f, e),
+            },
+            None => Some("prelude.orn".to_string()),
         }
     };
+    let paths = matches
+        .values_of("path")
+        .into_iter()
+        .flatten()
+        .map(|p| p.to_string())
+        .chain(env::var_os("ORION_PATH").iter().flat_map(env::split_paths).map(|p| p.to_string_lossy().to_string()))
+        .collect::<Vec<String>>();
     let dbg_level = match matches.value_of("debug-level") {
         Some(lvl) => match lvl.parse::<u8>() {
             Ok(u) => if u > 3 {
@@ -199,7 +325,26 @@ pub fn cli() -> Result<()> {
         }
         None => 0,
     };
-    if let Some(file) = matches.value_of("file") {
+    let limits = Limits {
+        stack: numeric(&matches, "max-stack")?,
+        depth: numeric(&matches, "max-depth")?,
+        fuel: numeric(&matches, "fuel")?,
+        timeout: numeric(&matches, "timeout")?.map(Duration::from_millis),
+        memory: numeric(&matches, "max-memory")?,
+    };
+    if let Some(deps) = matches.subcommand_matches("deps") {
+        let file = deps.value_of("file").unwrap();
+        let content = match fs::read_to_string(file) {
+            Ok(s) => s,
+            Err(e) => return error!(=> "Failed to read file: {}: {}.", file, e)
+        };
+        let tokens = Lexer::new(content, file).proc_tokens()?;
+        let expressions = Parser::new(tokens, file).parse()?;
+        let mut compiler = Compiler::new(expressions, file, Bytecode::new(), vec![], false, lib, false, vec![])?.with_paths(paths).with_prelude(prelude);
+        compiler.compile(vec![])?;
+        let graph = compiler.dependencies();
+        print_dependencies(graph, &graph[0].0, "", &mut vec![]);
+    } else if let Some(file) = matches.value_of("file") {
         let output = match matches.value_of("output") {
             Some(f) => f.to_string(),
             None => format!("{}.orc", Path::new(file).file_stem().unwrap().to_str().unwrap()),
@@ -211,7 +356,10 @@ pub fn cli() -> Result<()> {
         let start = Instant::now();
         let tokens = Lexer::new(content, file).proc_tokens()?;
         let expressions = Parser::new(tokens, file).parse()?;
-        let (bytecode, ..) = Compiler::new(expressions, file, Bytecode::new(), vec![], false, lib, false, vec![])?.compile(vec![])?;
+        let mut compiler = Compiler::new(expressions, file, Bytecode::new(), vec![], false, lib, false, vec![])?.with_paths(paths).with_prelude(prelude).with_limits(limits).with_optimizations(matches.is_present("optimize")).with_tree_shaking(matches.is_present("optimize"));
+        let compiled = compiler.compile(vec![]);
+        compiler.warnings.drain(..).for_each(print_warning);
+        let (bytecode, _, _, macros) = compiled?;
         let elapsed = start.elapsed();
         if dbg_level > 0 {
             println!("{} Compiled in {}ms.", STAR, elapsed.as_millis());
@@ -225,10 +373,13 @@ pub fn cli() -> Result<()> {
             Err(e) => return error!(=> "Failed to write file: {}: {}.", output, e),
         };
         if !matches.is_present("compile-only") {
-            VM::<16000>::new(bytecode, vec![]).eval(vec![], vec![], dbg_level > 2)?;
+            let mut vm = VM::<STACK_SIZE>::new(bytecode).with_limits(limits);
+            vm.macros = macros;
+            vm.namespaces = compiler.namespaces();
+            vm.eval(vec![], dbg_level > 2)?;
         }
     } else {
-        repl(dbg_level, lib)?;
+        repl(dbg_level, lib, paths, prelude, limits, matches.is_present("optimize"))?;
     }
     Ok(())
 }
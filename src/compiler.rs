@@ -19,45 +19,94 @@
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::{
-    bytecode::{BytecodePattern, Bytecode, Chunk, OpCode},
+    bytecode::{Bytecode, Chunk, Match, OpCode, Slot},
+    decision::{Builder, TreePattern},
     error,
+    exhaustiveness::{Matrix, Pat},
     lexer::Lexer,
-    parser::{Expr, ExprT, Literal, Parser, Pattern as ParserPattern},
-    Result,
+    library,
+    bug,
+    macros::{Expander, MACRO_STACK_SIZE, MAX_EXPANSION_DEPTH},
+    modules::{Module, Namespace},
+    optimizer::{fold, matches, Known},
+    parser::{Expr, ExprT, Literal, MacroParam, Parser, Pattern as ParserPattern},
+    quote::{quote, quote_list, unquote},
+    resolver::{Resolver, ScopeKind},
+    shaker::shake,
+    vm::{Globals, Limits, VM},
+    OrionError, Result,
 };
-use std::{fs, path::Path};
+use std::{fs, path::Path, rc::Rc};
 #[derive(Clone)]
-pub struct Macro {
-    pub args: Vec<String>,
-    pub content: Box<Expr>,
+pub enum Macro {
+    Template(Vec<(MacroParam, Expr)>), // ([(params, body)])
+    Procedural(MacroParam, Expr), // (params, body)
 }
 pub struct Compiler {
     input: Vec<Expr>,
     output: Bytecode,
     load_history: Vec<String>,
+    loading: Vec<String>, // The chain of files being loaded, from the compiled file.
+    dependencies: Vec<(String, Vec<String>)>, // (file, [loaded files])
     builtins: Vec<(String, bool)>, // (name, impure?)
     constructors: Vec<String>,
     file: String,
     lib: String,
+    paths: Vec<String>, // The search directories given with `-L` and $ORION_PATH.
+    prelude: Option<String>, // The file loaded before the input, if not loaded yet.
     repl: bool,
     macros: Vec<(String, Macro)>,
+    expansions: usize,
+    gensym: usize,
+    definitions: Vec<OpCode>,
+    globals: Globals, // The values of the top-level definitions, those of the previous compilations and the ones run by the procedural macros so far.
+    evaluated: usize, // The amount of instructions of `definitions` already run.
+    expressions: Vec<Vec<OpCode>>, // The code of each top-level expression, loaded files included, for the tree-shaking.
+    modules: Vec<Module>,
+    namespace: Namespace,
+    symbols: Vec<(String, bool)>, // (name, impure?)
+    resolver: Resolver,
+    limits: Limits, // Those of the procedural macros.
+    optimize: bool,
+    shake: bool,
+    wrappers: Vec<(u16, String, usize)>, // (sym_id, builtin, arity), the definitions only passing their arguments to a builtin.
+    redefined: Vec<u16>, // The symbols defined more than once, that are never inlined.
+    pub warnings: Vec<OrionError>,
 }
 
 impl Compiler {
     pub fn new(input: Vec<Expr>, file: impl ToString, mut bcode: Bytecode, constructors: Vec<String>, already_loaded: bool, lib: String, repl: bool, macros: Vec<(String, Macro)>) -> Result<Self> {
         bcode.instructions = vec![];
-        let mut new_input = if already_loaded { vec![] } else { vec![Expr::new(ExprT::Load(vec!["prelude.orn".to_string()])).line(0)]};
-        new_input.extend(input);
         let mut to_ret = Self {
-            input: new_input,
+            input,
+            prelude: if already_loaded { None } else { Some("prelude.orn".to_string()) },
             constructors,
             lib,
+            paths: vec![],
             macros,
+            expansions: 0,
+            gensym: 0,
+            definitions: vec![],
+            globals: vec![],
+            evaluated: 0,
+            expressions: vec![],
+            modules: vec![],
+            namespace: Namespace::default(),
+            symbols: vec![],
+            resolver: Resolver::default(),
+            limits: Limits::default(),
+            optimize: false,
+            shake: false,
+            wrappers: vec![],
+            redefined: vec![],
             repl,
             output: bcode,
             load_history: vec![],
+            loading: vec![identity(&file.to_string())],
+            dependencies: vec![(identity(&file.to_string()), vec![])],
             builtins: vec![],
             file: file.to_string(),
+            warnings: vec![],
         };
         to_ret.register_builtin("add", false);
         to_ret.register_builtin("sub", false);
@@ -79,9 +128,193 @@ impl Compiler {
 
         to_ret.register_builtin("type", false);
         to_ret.register_builtin("_cmp", false);
+        to_ret.register_builtin("_append", false);
 
         Ok(to_ret)
     }
+    // Gives the modules already compiled and the top-level namespace, when compiling the following lines of the REPL.
+    pub fn with_namespaces(self, (modules, namespace): (Vec<Module>, Namespace)) -> Self {
+        Self {
+            modules,
+            namespace,
+            ..self
+        }
+    }
+    // Gives the values of the definitions run before, such as the ones of the previous lines of the REPL, to the procedural macros.
+    pub fn with_globals(self, globals: Globals) -> Self {
+        Self {
+            globals,
+            ..self
+        }
+    }
+    // Sets the directories searched for the loaded files that are neither next to the loading file nor in the library.
+    pub fn with_paths(self, paths: Vec<String>) -> Self {
+        Self {
+            paths,
+            ..self
+        }
+    }
+    // Replaces the prelude by another file, or disables it, unless it has already been loaded.
+    pub fn with_prelude(self, prelude: Option<String>) -> Self {
+        Self {
+            prelude: self.prelude.and(prelude),
+            ..self
+        }
+    }
+    // Bounds the resources used by the procedural macros, as the code they run may be untrusted.
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Self {
+            limits,
+            ..self
+        }
+    }
+    pub fn with_optimizations(self, optimize: bool) -> Self {
+        Self {
+            optimize,
+            ..self
+        }
+    }
+    // Drops the definitions the compiled program does not use. Not for code compiled on top of other code, that may use them.
+    pub fn with_tree_shaking(self, shake: bool) -> Self {
+        Self {
+            shake,
+            ..self
+        }
+    }
+    // The files loaded by each file, starting with the compiled one.
+    pub fn dependencies(&self) -> &[(String, Vec<String>)] {
+        &self.dependencies
+    }
+    fn depend(&mut self, file: &str, dependency: &str) {
+        match self.dependencies.iter_mut().find(|(f, _)| f == file) {
+            Some((_, deps)) if deps.iter().any(|d| d == dependency) => {}
+            Some((_, deps)) => deps.push(dependency.to_string()),
+            None => self.dependencies.push((file.to_string(), vec![dependency.to_string()])),
+        }
+        if !self.dependencies.iter().any(|(f, _)| f == dependency) {
+            self.dependencies.push((dependency.to_string(), vec![]));
+        }
+    }
+    pub fn namespaces(&self) -> (Vec<Module>, Namespace) {
+        (self.modules.clone(), self.namespace.clone())
+    }
+    // Returns the symbol a variable refers to, from the local variables, the namespace or a `module.name` qualified access.
+    fn resolve(&self, name: &str, line: usize) -> Result<String> {
+        if let Some(qualified) = self.namespace.lookup(name) {
+            return Ok(qualified.to_string());
+        }
+        match name.split_once('.') {
+            Some((prefix, member)) if !prefix.is_empty() && !member.is_empty() => {
+                let module = self.namespace.module_of(prefix).unwrap_or(prefix);
+                let current = self.namespace.module.as_ref().filter(|m| m.name == module);
+                match current.or_else(|| self.modules.iter().find(|m| m.name == module)) {
+                    Some(m) if self.exported_constructor(m, member) => Ok(member.to_string()),
+                    Some(m) if current.is_some() || m.exports.iter().any(|e| e == member) => Ok(m.qualify(member)),
+                    Some(m) => error!(self.file, line => "{} is not exported by module {}.", member, m.name),
+                    None => Ok(name.to_string()),
+                }
+            }
+            _ => Ok(name.to_string()),
+        }
+    }
+    // Whether a name is a constructor of an enumeration exported by a module, that keeps its unqualified name.
+    fn exported_constructor(&self, module: &Module, name: &str) -> bool {
+        self.constructors.iter().enumerate().any(|(idx, constr)| {
+            constr == name && self.output.types.iter().any(|(t, start, end)| module.exports.contains(t) && (*start..=*end).contains(&(idx as u16)))
+        })
+    }
+    // Returns the symbol of a top-level definition, qualified by the module being compiled.
+    fn define(&mut self, name: &str) -> String {
+        match &self.namespace.module {
+            Some(module) => {
+                let qualified = module.qualify(name);
+                self.namespace.definitions.push(name.to_string());
+                self.namespace.bind(name, &qualified);
+                qualified
+            }
+            None => {
+                if self.namespace.is_bound(name) {
+                    self.namespace.bind(name, name);
+                }
+                name.to_string()
+            }
+        }
+    }
+    // Declares the top-level definitions and enumerations of a file before compiling it, so that they can be referred to before being defined.
+    fn hoist(&mut self, expressions: &[Expr]) -> Result<()> {
+        for expr in expressions {
+            match &expr.exprt {
+                ExprT::Def(name, _, purity) => {
+                    let qualified = self.define(name);
+                    if let Some(idx) = self.symbols.iter().position(|(s, _)| s == &qualified) {
+                        self.redefined.push(idx as u16);
+                    }
+                    if self.namespace.module.is_none() {
+                        // Shadows the exports of the modules loaded by the file.
+                        self.namespace.definitions.push(name.clone());
+                        self.namespace.bind(name, name);
+                    }
+                    self.declare(qualified, *purity, expr.line)?;
+                }
+                ExprT::Enum(..) => {
+                    self.compile_expr(expr.clone(), false)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    // Binds the exported values of a module in the current namespace, unless the current file defines them.
+    fn bind_exports(&mut self, module: &Module, names: &[String]) {
+        for name in names {
+            let qualified = module.qualify(name);
+            if self.symbols.iter().any(|(s, _)| s == &qualified) && !self.namespace.definitions.contains(name) {
+                self.namespace.bind(name, qualified);
+            }
+        }
+    }
+    // Searches a loaded file in the directory of the loading file, then in the search paths, in the library and finally in the embedded library.
+    fn find_file(&self, file: &str, line: usize) -> Result<String> {
+        let relative = Path::new(&self.file).parent().map_or(String::new(), |p| p.to_string_lossy().to_string());
+        let directories = std::iter::once(relative)
+            .chain(self.paths.iter().cloned())
+            .chain(Some(self.lib.clone()).filter(|l| !l.is_empty()))
+            .chain(Some(library::ROOT.to_string()))
+            .collect::<Vec<String>>();
+        self.search(file, &directories, line)
+    }
+    // Searches a file of the standard library, in the library folder and then in the embedded library.
+    fn find_library_file(&self, file: &str, line: usize) -> Result<String> {
+        let directories = Some(self.lib.clone())
+            .filter(|l| !l.is_empty())
+            .into_iter()
+            .chain(Some(library::ROOT.to_string()))
+            .collect::<Vec<String>>();
+        self.search(file, &directories, line)
+    }
+    fn search(&self, file: &str, directories: &[String], line: usize) -> Result<String> {
+        if library::read(file).is_some() {
+            return Ok(file.to_string());
+        } else if Path::new(file).is_absolute() {
+            return if Path::new(file).exists() {
+                Ok(file.to_string())
+            } else {
+                error!(self.file, line => "File not found: {}.", file)
+            };
+        }
+        for directory in directories {
+            let path = Path::new(directory).join(file).to_string_lossy().to_string();
+            if library::read(&path).is_some() || Path::new(&path).exists() {
+                return Ok(path);
+            }
+        }
+        let searched = directories
+            .iter()
+            .map(|d| if d.is_empty() { "." } else { d.as_str() })
+            .collect::<Vec<&str>>()
+            .join(", ");
+        error!(self.file, line => "File not found: {}, searched in: {}.", file, searched)
+    }
     fn register_builtin(&mut self, name: impl ToString, impure: bool) {
         self.builtins.push((name.to_string(), impure))
     }
@@ -100,7 +333,7 @@ impl Compiler {
                .unwrap() as u16)
         }
     }
-    fn register_constructor(&mut self, name: impl ToString, symbols: Vec<(String, bool)>, contained_amount: u8, line: usize) -> Result<Vec<(String, bool)>> {
+    fn register_constructor(&mut self, name: impl ToString, contained_amount: u8, line: usize) -> Result<()> {
         let name = name.to_string();
         if self.constructors.contains(&name) {
             error!(
@@ -115,13 +348,14 @@ impl Compiler {
                 )
         } else {
             self.constructors.push(name.clone());
-            let (idx, symbols) = self.declare(name, symbols, false, line)?;
+            let idx = self.declare(name, false, line)?;
             self.output.constructors.push((contained_amount, idx));
-            Ok(symbols)
+            Ok(())
         }
     }
     fn get_constructor(&self, name: impl ToString, line: usize) -> Result<(u8, u16)> {
         let name = name.to_string();
+        let name = self.resolve(&name, line)?;
         if self.constructors.contains(&name) {
             let idx = self
                 .constructors
@@ -133,66 +367,90 @@ impl Compiler {
             error!(self.file, line => "Enum variant {} does not exist.", name)
         }
     }
-    fn declare(
-        &mut self,
-        name: impl ToString,
-        mut symbols: Vec<(String, bool)>,
-        impure: bool,
-        line: usize,
-        ) -> Result<(u16, Vec<(String, bool)>)> {
-        if symbols.len() >= u16::MAX as usize {
-            error!(self.file, line => "Too much symbols are declared.")
-        } else {
-            Ok((
-                    if symbols.contains(&(name.to_string(), impure))
-                    || symbols.contains(&(name.to_string(), !impure))
-                    {
-                        symbols
-                            .iter()
-                            .position(|s| s.0 == name.to_string())
-                            .unwrap()
-                    } else {
-                        symbols.push((name.to_string(), impure));
-                        symbols.len() - 1
-                    } as u16,
-                    symbols,
-                    ))
+    fn declare(&mut self, name: impl ToString, impure: bool, line: usize) -> Result<u16> {
+        let name = name.to_string();
+        match self.symbols.iter().position(|s| s.0 == name) {
+            Some(idx) => Ok(idx as u16),
+            None if self.symbols.len() >= u16::MAX as usize => error!(self.file, line => "Too much symbols are declared."),
+            None => {
+                self.symbols.push((name, impure));
+                Ok(self.symbols.len() as u16 - 1)
+            }
         }
     }
     fn load_file(
         &mut self,
         fname: impl ToString,
-        mut symbols: Vec<(String, bool)>,
         line: usize,
-        ) -> Result<(Vec<OpCode>, Vec<(String, bool)>)> {
+        ) -> Result<(Vec<OpCode>, Option<Module>)> {
         let fname = fname.to_string();
-        if self.load_history.contains(&fname) {
+        let file = identity(&fname);
+        if let Some(loader) = self.loading.last().cloned() {
+            self.depend(&loader, &file);
+        }
+        if let Some(start) = self.loading.iter().position(|f| f == &file) {
+            let chain = self.loading[start..].iter().chain(Some(&file)).map(|f| relative(f)).collect::<Vec<String>>();
+            error!(self.file, line => "Circular load: {}.", chain.join(" -> "))
+        } else if let Some(module) = self.modules.iter().find(|m| m.file == file) {
+            // Modules are compiled once, whatever the amount of files importing them.
+            Ok((vec![], Some(module.clone())))
+        } else if self.load_history.contains(&file) {
             // Avoid error-prone reloading if file has already been loaded.
-            Ok((vec![], symbols))
+            Ok((vec![], None))
         } else {
-            self.load_history.push(fname.clone());
-            match fs::read_to_string(&fname) {
+            self.load_history.push(file.clone());
+            self.loading.push(file.clone());
+            match library::read(&fname).map_or_else(|| fs::read_to_string(&fname), |c| Ok(c.to_string())) {
                 Ok(content) => {
                     let tokens = Lexer::new(content, &fname).proc_tokens()?;
-                    let expressions = Parser::new(tokens, &fname).parse()?;
+                    let mut expressions = Parser::new(tokens, &fname).parse()?;
+                    let module = match expressions.first().map(|e| e.exprt.clone()) {
+                        Some(ExprT::Module(name, exports)) => Some((Module { name, file: file.clone(), exports }, expressions.remove(0).line)),
+                        _ => None,
+                    };
                     let saved = self.file.clone();
+                    let saved_namespace = match &module {
+                        Some((module, _)) => {
+                            let namespace = Namespace::new(module.clone(), &self.namespace);
+                            Some(std::mem::replace(&mut self.namespace, namespace))
+                        }
+                        None => None,
+                    };
                     self.file = fname;
-                    let to_ret = Ok((
-                            expressions
-                            .into_iter()
-                            .map(|e| {
-                                let to_ret = self.compile_expr(e, symbols.clone(), true)?;
-                                symbols = to_ret.1; // Update symbols.
-                                Ok(to_ret.0)
-                            })
-                            .collect::<Result<Vec<Vec<OpCode>>>>()?
-                            .into_iter()
-                            .flatten()
-                            .collect(),
-                            symbols,
-                            ));
+                    let scope = self.resolver.suspend();
+                    self.hoist(&expressions)?;
+                    let instructions = expressions
+                        .into_iter()
+                        .filter(|e| !matches!(e.exprt, ExprT::Enum(..))) // Already compiled.
+                        .map(|e| {
+                            let is_load = matches!(e.exprt, ExprT::Load(_) | ExprT::Import(..));
+                            let instructions = self.compile_expr(e, true)?;
+                            if !is_load {
+                                self.record_definitions(&instructions);
+                            }
+                            Ok(instructions)
+                        })
+                        .collect::<Result<Vec<Vec<OpCode>>>>()?
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                    let module = match module {
+                        Some((module, line)) => {
+                            if let Some(missing) = module.exports.iter().find(|e| !self.namespace.definitions.contains(e)) {
+                                return error!(self.file, line => "Module {} exports {}, that it does not define.", module.name, missing);
+                            }
+                            self.modules.push(module.clone());
+                            Some(module)
+                        }
+                        None => None,
+                    };
+                    if let Some(namespace) = saved_namespace {
+                        self.namespace = namespace;
+                    }
                     self.file = saved;
-                    to_ret
+                    self.resolver.resume(scope);
+                    self.loading.pop();
+                    Ok((instructions, module))
                 }
                 Err(e) => error!(self.file, line => "Failed to read file: {}: {}.", fname, e),
             }
@@ -202,172 +460,313 @@ impl Compiler {
         &mut self,
         idx: usize,
         args: Vec<Expr>,
-        symbols: Vec<(String, bool)>,
         impure: bool,
         line: usize,
-        ) -> Result<(Vec<OpCode>, Vec<(String, bool)>)> {
-        let content = self.macros[idx].1.clone();
-        if content.args.len() != args.len() {
-            error!(self.file, line => "Expected {} arguments, found {}.", content.args.len(), args.len())
-        } else {
-            let mut expr = *content.content;
-            for (idx, arg) in args.into_iter().enumerate() {
-                expr = expr.replace(content.args[idx].clone(), arg);
+        ) -> Result<Vec<OpCode>> {
+        let (name, content) = self.macros[idx].clone();
+        if self.expansions >= MAX_EXPANSION_DEPTH {
+            return error!(self.file, line => "Maximum macro expansion depth exceeded while expanding {}.", name);
+        }
+        let procedural = matches!(content, Macro::Procedural(..));
+        let clauses = match content {
+            Macro::Template(clauses) => clauses,
+            Macro::Procedural(params, body) => vec![(params, body)],
+        };
+        let expanded = match Expander::new(&clauses, &args, &mut self.gensym, self.file.clone(), line) {
+            Some((expander, body)) if procedural => {
+                let arguments = expander.arguments();
+                self.procedural(&name, arguments, body, line)?
             }
-            self.compile_expr(expr, symbols, impure)
+            Some((mut expander, body)) => expander.expand(&body)?,
+            None => return match clauses.as_slice() {
+                [(MacroParam::List(params, None), _)] if params.iter().all(|p| matches!(p, MacroParam::Var(_))) => {
+                    error!(self.file, line => "Expected {} arguments, found {}.", params.len(), args.len())
+                }
+                _ => error!(self.file, line => "No clause of macro {} matches the arguments.", name),
+            }
+        };
+        self.expansions += 1;
+        let compiled = self.compile_expr(expanded, impure);
+        self.expansions -= 1;
+        compiled
+    }
+    // Runs the body of a procedural macro in an embedded VM, with its arguments quoted, and reads back the code it returns.
+    fn procedural(&mut self, name: &str, arguments: Vec<(String, Vec<Expr>, bool)>, body: Expr, line: usize) -> Result<Expr> {
+        let (names, quoted) = arguments.into_iter().map(|(name, exprs, rest)| {
+            (name, if rest { quote_list(&exprs, line) } else { quote(&exprs[0]) })
+        }).unzip::<_, _, Vec<String>, Vec<Expr>>();
+        let call = Expr::new(ExprT::Call(Box::new(Expr::new(ExprT::Lambda(names, Box::new(body))).line(line)), quoted)).line(line);
+        // Compiled out of the scopes of the macro call, as it runs on its own.
+        let scope = self.resolver.suspend();
+        let instructions = self.compile_expr(call, false);
+        self.resolver.resume(scope);
+        let instructions = instructions?;
+
+        let mut bytecode = self.output.clone();
+        bytecode.symbols = self.symbols.iter().map(|(name, _)| name.clone()).collect();
+        // Only the definitions compiled since the last expansion are run, the others being already evaluated.
+        bytecode.instructions = self.definitions[self.evaluated..].to_vec();
+        bytecode.instructions.extend(instructions);
+        let constructors = bytecode.constructors.iter().map(|(_, sym)| bytecode.symbols[*sym as usize].clone()).collect::<Vec<String>>();
+
+        let file = self.file.clone();
+        let in_macro = |e: OrionError| OrionError(Some(file.clone()), Some(line), format!("In macro {}: {}", name, e.2));
+        let mut vm = VM::<MACRO_STACK_SIZE>::new(bytecode).with_limits(self.limits);
+        self.globals = vm.eval(self.globals.clone(), false).map_err(in_macro)?;
+        self.evaluated = self.definitions.len();
+        let code = vm.pop().map_err(in_macro)?;
+        let tokens = unquote(&code, &constructors, &self.file, line)?;
+        let mut exprs = Parser::new(tokens, &self.file).parse()?;
+        if exprs.len() != 1 {
+            return error!(self.file, line => "Procedural macro {} has to return exactly one expression.", name);
         }
+        Ok(exprs.remove(0))
     }
-    fn compile_expr(
-        &mut self,
-        expr: Expr,
-        mut symbols: Vec<(String, bool)>,
-        impure: bool,
-        ) -> Result<(Vec<OpCode>, Vec<(String, bool)>)> {
+    // Keeps the top-level definitions, so that procedural macros can use the functions defined before them, and the code of every top-level expression for the tree-shaking.
+    fn record_definitions(&mut self, instructions: &[OpCode]) {
+        if self.shake {
+            self.expressions.push(instructions.to_vec());
+        }
+        if let Some(OpCode::Def(_)) = instructions.last() {
+            self.definitions.extend_from_slice(instructions);
+        }
+    }
+    // Returns the slot of a variable, from the enclosing scopes or the global ones.
+    fn variable(&mut self, name: &str, impure: bool, line: usize) -> Result<Slot> {
+        if let Some((binding, slot)) = self.resolver.lookup(name) {
+            return if binding.impure && !impure {
+                error!(self.file, line => "Impure function used out of an `impure` declaration: {}", name)
+            } else {
+                Ok(slot)
+            };
+        }
+        let qualified = self.resolve(name, line)?;
+        match self.symbols.iter().find(|(n, _)| n == &qualified) {
+            Some((_, true)) if !impure => error!(self.file, line => "Impure function used out of an `impure` declaration: {}", qualified),
+            Some(_) => Ok(Slot::Global(self.declare(qualified, impure, line)?)),
+            None => match self.resolver.elsewhere(name) {
+                Some(scope) => error!(self.file, line => "Variable not in scope: {} (only bound in the {} at line {}).", name, scope.kind, scope.line),
+                None => error!(self.file, line => "Variable not in scope: {}.", qualified),
+            },
+        }
+    }
+    // Returns the slot of a variable bound by a pattern, shared by the alternatives of or-patterns.
+    fn pattern_variable(&mut self, name: &str, impure: bool) -> u16 {
+        match self.resolver.local(name) {
+            Some(binding) => binding.slot,
+            None => self.resolver.bind(name, impure),
+        }
+    }
+    // Records a top-level function that only passes its arguments to a pure builtin, in the same order, to call the builtin instead.
+    fn record_wrapper(&mut self, idx: u16, value: &Expr) {
+        self.wrappers.retain(|(sym, ..)| *sym != idx);
+        if self.redefined.contains(&idx) {
+            return;
+        }
+        let (params, body) = match &value.exprt {
+            ExprT::Lambda(params, body) => (params, body),
+            _ => return,
+        };
+        let (builtin, args) = match &body.exprt {
+            ExprT::Builtin(name, args) => (name.clone(), args),
+            ExprT::Call(func, args) => match &func.exprt {
+                ExprT::Var(f) if !params.contains(f) => match self.wrapper(f, args.len()) {
+                    Some(builtin) => (builtin, args),
+                    None => return,
+                },
+                _ => return,
+            },
+            _ => return,
+        };
+        let forwarded = args.len() == params.len() && args.iter().zip(params).all(|(arg, param)| matches!(&arg.exprt, ExprT::Var(v) if v == param));
+        if forwarded && self.builtins.iter().any(|(name, impure)| name == &builtin && !impure) {
+            self.wrappers.push((idx, builtin, params.len()));
+        }
+    }
+    // The builtin that a call to a name amounts to, when it refers to a recorded wrapper taking that many arguments.
+    fn wrapper(&mut self, name: &str, argc: usize) -> Option<String> {
+        if !self.optimize || self.macros.iter().any(|(n, _)| n == name) || self.resolver.lookup(name).is_some() {
+            return None;
+        }
+        let qualified = self.resolve(name, 0).ok()?;
+        let idx = self.symbols.iter().position(|(s, _)| s == &qualified)? as u16;
+        self.wrappers.iter().find(|(sym, _, arity)| *sym == idx && *arity == argc).map(|(_, builtin, _)| builtin.clone())
+    }
+    // The value of an expression made of literals and of pure builtin calls, computed at compile time.
+    fn constant(&mut self, expr: &Expr) -> Option<Literal> {
+        let (builtin, args) = match &expr.exprt {
+            ExprT::Literal(lit) => return Some(lit.clone()),
+            ExprT::Builtin(name, args) => (name.clone(), args),
+            ExprT::Call(func, args) => match &func.exprt {
+                ExprT::Var(f) => (self.wrapper(f, args.len())?, args),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let args = args.iter().map(|arg| self.constant(arg)).collect::<Option<Vec<Literal>>>()?;
+        fold(&builtin, &args)
+    }
+    // The value of an expression known at compile time, without running anything.
+    fn known(&mut self, expr: &Expr) -> Option<Known> {
+        if let Some(lit) = self.constant(expr) {
+            return Some(Known::Literal(lit));
+        }
+        match &expr.exprt {
+            ExprT::Constr(name, values) => {
+                let idx = self.get_constructor(name, expr.line).ok()?.1;
+                Some(Known::Constr(idx, values.iter().map(|v| self.known(v)).collect::<Option<Vec<Known>>>()?))
+            }
+            ExprT::Tuple(values) => Some(Known::Tuple(values.iter().map(|v| self.known(v)).collect::<Option<Vec<Known>>>()?)),
+            _ => None,
+        }
+    }
+    fn compile_expr(&mut self, expr: Expr, impure: bool) -> Result<Vec<OpCode>> {
         match expr.exprt.clone() {
-            ExprT::Literal(lit) => Ok((
-                    vec![(OpCode::LoadConst(self.register_constant(lit, expr.line)?))],
-                    symbols,
-                    )),
+            ExprT::Literal(lit) => Ok(vec![(OpCode::LoadConst(self.register_constant(lit, expr.line)?))]),
             ExprT::Var(name) => {
                 if name.as_str() == "__LINE__" {
-                    self.compile_expr(Expr::new(ExprT::Literal(Literal::Integer(expr.line as i32))).line(expr.line), symbols, impure)
+                    self.compile_expr(Expr::new(ExprT::Literal(Literal::Integer(expr.line as i32))).line(expr.line), impure)
                 } else if name.as_str() == "__FILE__" {
-                    self.compile_expr(Expr::new(ExprT::Literal(Literal::String(self.file.clone()))).line(expr.line), symbols, impure)
-                } else if !symbols.contains(&(name.clone(), impure)) {
-                    if impure && symbols.contains(&(name.clone(), false)) {
-                        let (idx, symbols) = self.declare(name, symbols, impure, expr.line)?;
-                        Ok((vec![OpCode::LoadSym(idx)], symbols))
-                    } else if !impure && symbols.contains(&(name.clone(), true)) {
-                        error!(
-                            self.file,
-                            expr.line =>
-                            "Impure function used out of an `impure` declaration: {}",
-                            name
-                            )
-                    } else {
-                        error!(self.file, expr.line => "Variable not in scope: {}.", name)
-                    }
+                    self.compile_expr(Expr::new(ExprT::Literal(Literal::String(self.file.clone()))).line(expr.line), impure)
                 } else {
-                    let (idx, symbols) = self.declare(name, symbols, impure, expr.line)?;
-                    Ok((vec![OpCode::LoadSym(idx)], symbols))
+                    Ok(vec![self.variable(&name, impure, expr.line)?.load()])
                 }
             }
             ExprT::Load(files) => {
-                let lib_link = self.lib.to_string();
-
-                let instrs = files
-                        .into_iter()
-                        .map(|file| {
-                            let lib_path = format!("{}/{}", lib_link, file);
-                            let fname = if Path::new(&lib_path).exists() {
-                                Ok(lib_path)
-                            } else if Path::new(&file).exists() {
-                                Ok(file)
-                            } else {
-                                error!(self.file, expr.line => "File not found: {}.", file)
-                            }?;
-
-                            let to_ret = self.load_file(fname, symbols.clone(), expr.line)?;
-                            symbols = to_ret.1; // Update symbols.
-                            Ok(to_ret.0)
-                        })
-                        .collect::<Result<Vec<Vec<OpCode>>>>()?
-                        .into_iter()
-                        .flatten()
-                        .collect::<Vec<OpCode>>();
-
-                Ok((
-                        instrs,
-                        symbols,
-                        ))
+                Ok(files
+                    .into_iter()
+                    .map(|file| {
+                        let fname = self.find_file(&file, expr.line)?;
+                        let (instructions, module) = self.load_file(fname, expr.line)?;
+                        if let Some(module) = module {
+                            self.bind_exports(&module, &module.exports);
+                        }
+                        Ok(instructions)
+                    })
+                    .collect::<Result<Vec<Vec<OpCode>>>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<OpCode>>())
+            }
+            ExprT::Import(name, alias, only) => {
+                let (instructions, module) = match self.modules.iter().find(|m| m.name == name).cloned() {
+                    Some(module) => (vec![], module),
+                    None => {
+                        let fname = self.find_file(&format!("{}.orn", name), expr.line)?;
+                        match self.load_file(&fname, expr.line)? {
+                            (instructions, Some(module)) if module.name == name => (instructions, module),
+                            _ => return error!(self.file, expr.line => "{} does not declare module {}.", fname, name),
+                        }
+                    }
+                };
+                if let Some(alias) = alias {
+                    self.namespace.alias(alias, &module.name);
+                }
+                if let Some(only) = only {
+                    if let Some(missing) = only.iter().find(|n| !module.exports.contains(n)) {
+                        return error!(self.file, expr.line => "{} is not exported by module {}.", missing, module.name);
+                    }
+                    self.bind_exports(&module, &only);
+                }
+                Ok(instructions)
             }
+            ExprT::Module(..) => error!(self.file, expr.line => "A module declaration has to be the first expression of a loaded file."),
             ExprT::Def(name, value, purity) => {
-                let (idx, symbols) = self.declare(name, symbols, purity, expr.line)?;
-                let (to_push, symbols) = self.compile_expr(*value, symbols, purity)?; // Update symbols.
-                let mut to_ret = vec![OpCode::Def(idx, to_push.len() as u16)];
-                to_ret.extend(to_push);
-                Ok((to_ret, symbols))
+                let (def, idx, mut to_ret): (fn(u16) -> OpCode, _, _) = if self.resolver.is_top_level() {
+                    let name = self.define(&name);
+                    let idx = self.declare(name, purity, expr.line)?;
+                    if self.optimize && !purity {
+                        self.record_wrapper(idx, &value);
+                    }
+                    (OpCode::Def, idx, self.compile_expr(*value, purity)?)
+                } else if matches!(value.exprt, ExprT::Lambda(..)) {
+                    // Bound before compiling the function, so that it can be recursive.
+                    let slot = self.resolver.bind(&name, purity);
+                    (OpCode::DefLocal, slot, self.compile_expr(*value, purity)?)
+                } else {
+                    // The value can refer to the variable it shadows.
+                    let to_push = self.compile_expr(*value, purity)?;
+                    (OpCode::DefLocal, self.resolver.bind(&name, purity), to_push)
+                };
+                to_ret.push(def(idx));
+                Ok(to_ret)
             }
             ExprT::Call(func, args) => {
                 if let ExprT::Var(v) = func.clone().exprt {
                     match self.macros.iter().position(|(name, ..)| &v == name) {
-                        Some(i) => return self.r#macro(i, args, symbols, impure, expr.line),
+                        Some(i) => return self.r#macro(i, args, impure, expr.line),
+                        None if ["#(", "#[", "#{", "#\""].contains(&v.as_str()) => return error!(self.file, expr.line => "No reader macro is bound to {}.", v),
                         None => {}
                     }
+                    if let Some(builtin) = self.wrapper(&v, args.len()) {
+                        return self.compile_expr(Expr::new(ExprT::Builtin(builtin, args)).line(expr.line), impure);
+                    }
                 }
-                let (mut to_ret, mut symbols) = self.compile_expr(*func, symbols, impure)?; // The λ to execute.
+                let mut to_ret = self.compile_expr(*func, impure)?; // The λ to execute.
                 let argc = args.len() as u16;
-                to_ret.extend(
-                    // Push arguments onto the stack, and keep the amount in order to pop all the arguments.
-                    args.into_iter()
-                    .map(|a| {
-                        let (opcodes, syms) = self.compile_expr(a, symbols.clone(), impure)?;
-                        symbols = syms; // Update symbols.
-                        Ok(opcodes)
-                    })
-                    .collect::<Result<Vec<Vec<OpCode>>>>()?
-                    .into_iter()
-                    .flatten()
-                    .collect::<Vec<OpCode>>(),
-                    );
+                // Push arguments onto the stack, and keep the amount in order to pop all the arguments.
+                for arg in args {
+                    to_ret.extend(self.compile_expr(arg, impure)?);
+                }
                 to_ret.push(OpCode::Call(argc));
-                Ok((to_ret, symbols))
+                Ok(to_ret)
+            }
+            ExprT::Begin(expressions) if self.optimize => {
+                // Its definitions take slots of the enclosing frame, instead of a function being created and called.
+                self.resolver.enter(ScopeKind::Block, expr.line);
+                let instructions = expressions
+                    .into_iter()
+                    .map(|expr| self.compile_expr(expr, impure))
+                    .collect::<Result<Vec<Vec<OpCode>>>>();
+                self.resolver.exit();
+                Ok(instructions?.into_iter().flatten().collect())
             }
             ExprT::Begin(expressions) => {
-                let instructions = expressions.into_iter().map(|expr| {
-                    let (instruction, new_syms) = self.compile_expr(expr, symbols.clone(), impure)?;
-                    symbols = new_syms;
-                    Ok(instruction)
-                }).collect::<Result<Vec<Vec<OpCode>>>>()?.into_iter().flatten().collect::<Vec<OpCode>>();
+                self.resolver.enter(ScopeKind::Begin, expr.line);
+                let instructions = expressions
+                    .into_iter()
+                    .map(|expr| self.compile_expr(expr, impure))
+                    .collect::<Result<Vec<Vec<OpCode>>>>();
+                let (slots, captures) = (self.resolver.slots(), self.resolver.captures());
+                self.resolver.exit();
+                let instructions = instructions?.into_iter().flatten().collect::<Vec<OpCode>>();
                 self.output.chunks.push(Chunk {
-                    instructions,
-                    reference: vec![],
+                    instructions: tail_calls(instructions).into(),
+                    arity: 0,
+                    slots,
+                    captures: captures.into(),
                 });
-                Ok((vec![OpCode::Lambda(self.output.chunks.len() as u16 - 1), OpCode::Call(0)], symbols))
+                Ok(vec![OpCode::Lambda(self.output.chunks.len() as u16 - 1), OpCode::Call(0)])
             }
             ExprT::Lambda(args, body) => {
-                let args_reference = args
-                    .iter()
-                    .map(|a| {
-                        let (idx, syms) = self.declare(a, symbols.clone(), false, expr.line)?;
-                        symbols = syms;
-                        Ok(idx)
-                    })
-                .collect::<Result<Vec<_>>>()?; // Position in the symbol table for each arg.
-                let run_with = symbols
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, sym)| {
-                        match args_reference.iter().position(|id| *id as usize == idx) {
-                            // Check if the current symbol is part of the arguments
-                            // If it is, then replace it by the argument's value, else
-                            // use the value that is already in the table.
-                            Some(arg_i) => (args[arg_i].to_string(), false),
-                            None => sym.clone(),
-                        }
-                    })
-                .collect::<Vec<(String, bool)>>();
-                let (chunk_instructions, symbols) = self.compile_expr(*body, run_with, impure)?;
+                if let Some(arg) = args.iter().enumerate().find(|(i, a)| args[..*i].contains(a)).map(|(_, a)| a) {
+                    return error!(self.file, expr.line => "Parameter {} is bound twice.", arg);
+                }
+                self.resolver.enter(ScopeKind::Function, expr.line);
+                // The arguments take the first slots of the frame.
+                args.iter().for_each(|a| {
+                    self.resolver.bind(a, false);
+                });
+                let compiled = self.compile_expr(*body, impure);
+                let (slots, captures) = (self.resolver.slots(), self.resolver.captures());
+                self.resolver.exit();
                 self.output.chunks.push(Chunk {
-                    instructions: chunk_instructions,
-                    reference: args_reference,
+                    instructions: tail_calls(compiled?).into(),
+                    arity: args.len() as u16,
+                    slots,
+                    captures: captures.into(),
                 });
-                Ok((
-                        vec![OpCode::Lambda(self.output.chunks.len() as u16 - 1)],
-                        symbols,
-                        ))
+                Ok(vec![OpCode::Lambda(self.output.chunks.len() as u16 - 1)])
             }
             ExprT::Builtin(name, args) => {
+                if let Some(folded) = self.constant(&expr).filter(|_| self.optimize) {
+                    return self.compile_expr(Expr::new(ExprT::Literal(folded)).line(expr.line), impure);
+                }
                 let argc = args.len();
                 let mut to_ret = args
                     .into_iter()
-                    .map(|arg| {
-                        let (compiled, new_syms) =
-                            self.compile_expr(arg, symbols.clone(), impure)?;
-                        symbols = new_syms; // Update symbols.
-                        Ok(compiled)
-                    })
-                .collect::<Result<Vec<Vec<OpCode>>>>()?
+                    .map(|arg| self.compile_expr(arg, impure))
+                    .collect::<Result<Vec<Vec<OpCode>>>>()?
                     .into_iter()
                     .flatten()
                     .collect::<Vec<OpCode>>();
@@ -378,7 +777,17 @@ impl Compiler {
                         return error!(self.file, expr.line => "Intrisic panic takes 1 argument but {} arguments were supplied.", argc)
                     } else {
                         to_ret.push(OpCode::Panic(self.register_constant(Literal::String(self.file.clone()), expr.line)?, self.register_constant(Literal::Integer(expr.line as i32), expr.line)?));
-                        return Ok((to_ret, symbols));
+                        return Ok(to_ret);
+                    }
+                }
+                if name.as_str() == "eval" {
+                    if argc != 1 {
+                        return error!(self.file, expr.line => "Intrisic eval takes 1 argument but {} arguments were supplied.", argc)
+                    } else if !impure {
+                        return error!(self.file, expr.line => "Impure builtin used out of an `impure` function: eval.")
+                    } else {
+                        to_ret.push(OpCode::Eval);
+                        return Ok(to_ret);
                     }
                 }
 
@@ -392,20 +801,32 @@ impl Compiler {
                     return error!(self.file, expr.line => "Impure builtin used out of an `impure` function: {}.", name);
                 }
                 to_ret.push(OpCode::Builtin(idx as u8, argc as u8));
-                Ok((to_ret, symbols))
+                Ok(to_ret)
             }
             ExprT::Enum(name, constructors) => {
+                // The constructors of the enums that a module does not export are qualified by its name.
+                let private = match &self.namespace.module {
+                    Some(module) if !module.exports.contains(&name) => Some(module.clone()),
+                    _ => None,
+                };
+                if self.namespace.module.is_some() {
+                    self.namespace.definitions.push(name.clone());
+                }
+                let name = private.as_ref().map_or(name.clone(), |m| m.qualify(&name));
                 let start = self.output.constructors.len() as u16;
-                constructors
-                    .into_iter()
-                    .map(|(k, v)| {
-                        symbols = self.register_constructor(k, symbols.clone(), v, expr.line)?;
-                        Ok(())
-                    })
-                .collect::<Result<()>>()?;
+                for (k, v) in constructors {
+                    let k = match &private {
+                        Some(module) => {
+                            self.namespace.bind(&k, module.qualify(&k));
+                            module.qualify(&k)
+                        }
+                        None => k,
+                    };
+                    self.register_constructor(k, v, expr.line)?;
+                }
                 let end = self.output.constructors.len() as u16 - 1;
                 self.output.types.push((name, start, end));
-                Ok((vec![], symbols))
+                Ok(vec![])
             }
             ExprT::Constr(name, contained) => {
                 let (amount, idx) = self.get_constructor(&name, expr.line)?;
@@ -420,51 +841,95 @@ impl Compiler {
                         contained.len()
                         )
                 } else {
-                    let values = contained
+                    let mut to_ret = contained
                         .into_iter()
-                        .map(|expr| {
-                            let (compiled, new_syms) =
-                                self.compile_expr(expr, symbols.clone(), impure)?;
-                            symbols = new_syms;
-                            Ok(compiled)
-                        })
-                    .collect::<Result<Vec<Vec<OpCode>>>>()?
+                        .map(|expr| self.compile_expr(expr, impure))
+                        .collect::<Result<Vec<Vec<OpCode>>>>()?
                         .into_iter()
                         .flatten()
                         .collect::<Vec<OpCode>>();
-                    let mut to_ret = vec![OpCode::Constructor(idx, values.len() as u16)];
-                    to_ret.extend(values);
-                    Ok((to_ret, symbols))
+                    to_ret.push(OpCode::Constructor(idx));
+                    Ok(to_ret)
                 }
             }
             ExprT::Tuple(vals) => {
                 let length = vals.len();
-                let values = vals
+                let mut to_ret = vals
                     .into_iter()
-                    .map(|expr| {
-                        let (compiled, new_syms) =
-                            self.compile_expr(expr, symbols.clone(), impure)?;
-                        symbols = new_syms;
-                        Ok(compiled)
-                    })
-                .collect::<Result<Vec<Vec<OpCode>>>>()?
+                    .map(|expr| self.compile_expr(expr, impure))
+                    .collect::<Result<Vec<Vec<OpCode>>>>()?
                     .into_iter()
                     .flatten()
                     .collect::<Vec<OpCode>>();
-                let op_count = values.len();
-                let mut to_ret = vec![OpCode::Tuple(op_count as u16, length as u16)];
-                to_ret.extend(values);
-                Ok((to_ret, symbols))
+                to_ret.push(OpCode::Tuple(length as u16));
+                Ok(to_ret)
             }
             ExprT::Match(expr, patterns) => {
-                let (mut compiled, mut symbols) = self.compile_expr(*expr.clone(), symbols, impure)?;
-                let match_content = patterns.into_iter().map(|(pat, expr)| {
-                    let (pat_id, new_symbols) = self.declare_pat(pat, symbols.clone(), impure, expr.line)?;
-                    symbols = new_symbols;
-                    let (compiled, new_syms) = self.compile_expr(expr, symbols.clone(), impure)?;
-                    symbols = new_syms;
-                    Ok((pat_id, compiled))
-                }).collect::<Result<Vec<(u16, Vec<OpCode>)>>>()?;
+                // A pin is compared before the arm binds anything, so it cannot name a variable of its own pattern.
+                for (pat, arm) in &patterns {
+                    if let Some(name) = pat.pins().into_iter().find(|name| pat.variables().contains(name)) {
+                        return error!(self.file, arm.line => "Pinned variable {} is bound by the same pattern.", name);
+                    }
+                }
+                let rows = patterns.iter().map(|(pat, _)| self.analysis_pattern(pat, expr.line)).collect::<Result<Vec<Pat>>>()?;
+                let lines = patterns.iter().map(|(_, arm)| arm.line).collect::<Vec<usize>>();
+                let known = if self.optimize { self.known(&expr) } else { None };
+                let patterns = match known {
+                    Some(value) => {
+                        // The arms that cannot match the value are dropped, and so are the ones after an arm that matches it.
+                        let (mut kept, mut matched) = (vec![], false);
+                        for (arm, row) in patterns.iter().zip(&rows) {
+                            match matches(row, &value) {
+                                Some(false) => {}
+                                outcome => {
+                                    kept.push(arm.clone());
+                                    if outcome.is_some() {
+                                        matched = true;
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                        match kept.as_slice() {
+                            // An arm that always matches without binding anything is all that is run.
+                            [(pat, arm)] if matched && pat.variables().is_empty() => {
+                                let arm = arm.clone();
+                                self.check_match(rows, lines, expr.line);
+                                return self.compile_expr(arm, impure);
+                            }
+                            [] => patterns,
+                            _ => kept,
+                        }
+                    }
+                    None => patterns,
+                };
+                let mut compiled = self.compile_expr(*expr.clone(), impure)?;
+                let (tree_patterns, arms) = patterns.into_iter().map(|(pat, expr)| {
+                    self.resolver.enter(ScopeKind::Arm, expr.line);
+                    let arm = self.tree_pattern(pat, impure, expr.line).and_then(|pat| Ok((pat, self.compile_expr(expr, impure)?)));
+                    self.resolver.exit();
+                    arm
+                }).collect::<Result<Vec<(TreePattern, Vec<OpCode>)>>>()?.into_iter().unzip::<_, _, Vec<_>, Vec<_>>();
+                let (tree, occurrences) = Builder::build(tree_patterns);
+                // The arms follow the `Match` instruction, each one jumping over the next ones.
+                let (mut offsets, mut jumps, mut code) = (vec![], vec![], vec![]);
+                let count = arms.len();
+                for (i, arm) in arms.into_iter().enumerate() {
+                    offsets.push(code.len() as u16);
+                    code.extend(arm);
+                    if i + 1 < count {
+                        jumps.push(code.len());
+                        code.push(OpCode::Jump(0));
+                    }
+                }
+                for jump in jumps {
+                    code[jump] = OpCode::Jump((code.len() - jump - 1) as u16);
+                }
+                let match_content = Rc::new(Match {
+                    occurrences,
+                    tree,
+                    arms: offsets,
+                });
 
                 let idx = if self.output.matches.contains(&match_content) {
                     self.output.matches.iter().position(|m| m == &match_content).unwrap()
@@ -473,59 +938,120 @@ impl Compiler {
                     self.output.matches.len() - 1
                 } as u16;
                 compiled.push(OpCode::Match(idx));
-                Ok((compiled, symbols))
+                compiled.extend(code);
+                self.check_match(rows, lines, expr.line);
+                Ok(compiled)
             }
-            ExprT::Macro(name, args, content) => {
-                let r#macro = Macro { args, content };
+            ExprT::Macro(_, _) | ExprT::ProcMacro(_, _, _) => {
+                let (name, r#macro) = match expr.exprt {
+                    ExprT::Macro(name, clauses) => (name, Macro::Template(clauses)),
+                    ExprT::ProcMacro(name, params, body) => (name, Macro::Procedural(params, *body)),
+                    _ => bug!("UNEXPECTED_NON_MACRO"),
+                };
+                if self.namespace.module.is_some() {
+                    self.namespace.definitions.push(name.clone());
+                }
                 match self.macros.iter().position(|(n, _)| n == &name) {
                     None => {
                         self.macros.push((name, r#macro));
-                        Ok((vec![], symbols))
+                        Ok(vec![])
                     }
                     Some(_) => error!(self.file, expr.line => "Macro has already been defined: {}.", name)
                 }
             }
+            ExprT::Reader(dispatch, name) => {
+                if self.macros.iter().any(|(n, _)| n == &dispatch) {
+                    return error!(self.file, expr.line => "Reader macro has already been defined: {}.", dispatch);
+                }
+                match self.macros.iter().find(|(n, _)| n == &name) {
+                    Some((_, r#macro)) => {
+                        let r#macro = r#macro.clone();
+                        self.macros.push((dispatch, r#macro));
+                        Ok(vec![])
+                    }
+                    None => error!(self.file, expr.line => "Macro not in scope: {}.", name),
+                }
+            }
         }
     }
-    fn declare_pat(&mut self, pat: ParserPattern, mut symbols: Vec<(String, bool)>, impure: bool, line: usize) -> Result<(u16, Vec<(String, bool)>)> {
-        let flattened = match pat {
+    fn tree_pattern(&mut self, pat: ParserPattern, impure: bool, line: usize) -> Result<TreePattern> {
+        Ok(match pat {
             ParserPattern::Var(s) => {
                 if s.as_str() == "_" {
-                    BytecodePattern::Any
+                    TreePattern::Any
                 } else {
-                    let (sym_id, new_symbols) = self.declare(s, symbols.clone(), impure, line)?;
-                    symbols = new_symbols;
-                    BytecodePattern::Var(sym_id)
+                    TreePattern::Bind(self.pattern_variable(&s, impure), Box::new(TreePattern::Any))
                 }
             }
             ParserPattern::Constr(constr, inside) => {
                 let (amount, constr_id)= self.get_constructor(constr, line)?;
                 self.check_constr(constr_id, amount, inside.len() as u8, line)?;
-                BytecodePattern::Constr(constr_id, inside.into_iter().map(|pat| {
-                    let (idx, new_syms) = self.declare_pat(pat, symbols.clone(), impure, line)?;
-                    symbols = new_syms;
-                    Ok(idx)
-                }).collect::<Result<Vec<u16>>>()?)
+                TreePattern::Constr(constr_id, inside.into_iter().map(|pat| self.tree_pattern(pat, impure, line)).collect::<Result<Vec<TreePattern>>>()?)
             }
             ParserPattern::Tuple(inside) => {
-                BytecodePattern::Tuple(inside.into_iter().map(|pat| {
-                    let (idx, new_syms) = self.declare_pat(pat, symbols.clone(), impure, line)?;
-                    symbols = new_syms;
-                    Ok(idx)
-                }).collect::<Result<Vec<u16>>>()?)
+                TreePattern::Tuple(inside.into_iter().map(|pat| self.tree_pattern(pat, impure, line)).collect::<Result<Vec<TreePattern>>>()?)
             }
             ParserPattern::Literal(lit) => {
-                let idx = self.register_constant(lit, line)?;
-                BytecodePattern::Literal(idx)
+                let idx = self.register_constant(lit.clone(), line)?;
+                TreePattern::Literal(idx, lit)
+            }
+            ParserPattern::Or(alternatives) => {
+                let expected = alternatives[0].variables();
+                if alternatives.iter().any(|alt| alt.variables() != expected) {
+                    return error!(self.file, line => "All the alternatives of an or-pattern have to bind the same variables.");
+                }
+                TreePattern::Or(alternatives.into_iter().map(|pat| self.tree_pattern(pat, impure, line)).collect::<Result<Vec<TreePattern>>>()?)
+            }
+            ParserPattern::Range(start, end) => {
+                match (&start, &end) {
+                    (Literal::Integer(_), Literal::Integer(_)) | (Literal::Single(_), Literal::Single(_)) => {}
+                    _ => return error!(self.file, line => "Range pattern bounds have to be two Integers or two Singles."),
+                }
+                TreePattern::Range(self.register_constant(start.clone(), line)?, self.register_constant(end.clone(), line)?, start, end)
             }
+            ParserPattern::Prefix(prefix, rest) => {
+                let prefix = self.register_constant(Literal::String(prefix), line)?;
+                TreePattern::Prefix(prefix, Box::new(self.tree_pattern(*rest, impure, line)?))
+            }
+            ParserPattern::Pin(name) => TreePattern::Pin(self.variable(&name, impure, line)?),
+            ParserPattern::As(name, pat) => {
+                let slot = self.pattern_variable(&name, impure);
+                TreePattern::Bind(slot, Box::new(self.tree_pattern(*pat, impure, line)?))
+            }
+        })
+    }
+    fn analysis_pattern(&self, pat: &ParserPattern, line: usize) -> Result<Pat> {
+        Ok(match pat {
+            ParserPattern::Var(_) => Pat::Any,
+            ParserPattern::Constr(name, inside) => Pat::Constr(self.get_constructor(name, line)?.1, inside.iter().map(|p| self.analysis_pattern(p, line)).collect::<Result<Vec<Pat>>>()?),
+            ParserPattern::Tuple(inside) => Pat::Tuple(inside.iter().map(|p| self.analysis_pattern(p, line)).collect::<Result<Vec<Pat>>>()?),
+            ParserPattern::Literal(lit) => Pat::Literal(lit.clone()),
+            ParserPattern::Or(alternatives) => Pat::Or(alternatives.iter().map(|p| self.analysis_pattern(p, line)).collect::<Result<Vec<Pat>>>()?),
+            ParserPattern::As(_, pat) => self.analysis_pattern(pat, line)?,
+            ParserPattern::Range(start, end) => Pat::Range(start.clone(), end.clone()),
+            ParserPattern::Prefix(prefix, _) => Pat::Prefix(prefix.clone()),
+            ParserPattern::Pin(name) => Pat::Opaque(format!("^{}", name)),
+        })
+    }
+    fn check_match(&mut self, patterns: Vec<Pat>, lines: Vec<usize>, line: usize) {
+        let rows = patterns.into_iter().map(|pat| vec![pat]).collect::<Vec<_>>();
+        let matrix = Matrix {
+            types: &self.output.types,
+            constructors: &self.output.constructors,
+            names: &self.constructors,
         };
-
-        Ok((if self.output.patterns.contains(&flattened) {
-            self.output.patterns.iter().position(|pat| pat == &flattened).unwrap() as u16
-        } else {
-            self.output.patterns.push(flattened);
-            self.output.patterns.len() as u16 - 1
-        }, symbols))
+        let mut warnings = vec![];
+        for (idx, row) in rows.iter().enumerate() {
+            if !matrix.useful(&rows[..idx], row) {
+                warnings.push(OrionError(Some(self.file.clone()), Some(lines[idx]), format!("Unreachable pattern: {}.", matrix.display(&row[0]))));
+            }
+        }
+        let missing = matrix.missing(&rows, 1);
+        if !missing.is_empty() {
+            let examples = missing.iter().map(|w| matrix.display(&w[0])).collect::<Vec<String>>().join(", ");
+            warnings.push(OrionError(Some(self.file.clone()), Some(line), format!("Non-exhaustive patterns, values not covered: {}.", examples)));
+        }
+        self.warnings.extend(warnings);
     }
     fn check_constr(&self, idx: u16, expected: u8, given: u8, line: usize) -> Result<()> {
         if given != expected {
@@ -534,36 +1060,378 @@ impl Compiler {
             Ok(())
         }
     }
-    pub fn compile(&mut self, mut symbols: Vec<(String, bool)>) -> Result<(Bytecode, Vec<(String, bool)>, Vec<String>, Vec<(String, Macro)>)> {
-        for expr in self.input.clone() {
-            let (to_push, new_symbols) = self.compile_expr(expr, symbols, self.repl)?;
-            symbols = new_symbols;
+    pub fn compile(&mut self, symbols: Vec<(String, bool)>) -> Result<(Bytecode, Vec<(String, bool)>, Vec<String>, Vec<(String, Macro)>)> {
+        let symbols = self.compile_input(symbols)?;
+
+        if self.output.symbols.contains(&"main".to_string()) {
+            self.output.instructions.extend(vec![OpCode::LoadGlobal(self.output.symbols.iter().position(|s| s == "main").unwrap() as u16), OpCode::Call(0)]);
+        }
+        if self.shake {
+            self.output = shake(std::mem::replace(&mut self.output, Bytecode::new()), &self.expressions);
+        }
+
+        Ok((self.output.clone(), symbols, self.constructors.clone(), self.macros.clone()))
+    }
+    // Compiles the input without calling `main`, for code evaluated at runtime.
+    pub fn compile_input(&mut self, symbols: Vec<(String, bool)>) -> Result<Vec<(String, bool)>> {
+        self.symbols = symbols;
+        if let Some(prelude) = self.prelude.take() {
+            // The default prelude is the one of the library, whatever the files next to the compiled one.
+            // Made absolute when on disk, as loads are resolved from the directory of the loading file.
+            let prelude = self.find_library_file(&prelude, 0)?;
+            let prelude = fs::canonicalize(&prelude).map_or(prelude, |p| p.to_string_lossy().to_string());
+            let to_push = self.compile_expr(Expr::new(ExprT::Load(vec![prelude])).line(0), self.repl)?;
             self.output.instructions.extend(to_push);
+            self.namespace.end_prelude();
         }
-        self.output.symbols = symbols
+        self.hoist(&self.input.clone())?;
+        for expr in self.input.clone().into_iter().filter(|e| !matches!(e.exprt, ExprT::Enum(..))) {
+            let is_load = matches!(expr.exprt, ExprT::Load(_) | ExprT::Import(..));
+            let to_push = self.compile_expr(expr, self.repl)?;
+            if !is_load {
+                self.record_definitions(&to_push);
+            }
+            self.output.instructions.extend(to_push);
+        }
+        self.output.symbols = self.symbols
             .iter()
             .map(|(name, _)| name.to_string())
             .collect::<Vec<String>>();
+        Ok(self.symbols.clone())
+    }
+    pub fn output(self) -> Bytecode {
+        self.output
+    }
+}
 
-        if self.output.symbols.contains(&"main".to_string()) {
-            self.output.instructions.extend(vec![OpCode::LoadSym(self.output.symbols.iter().position(|s| s == "main").unwrap() as u16), OpCode::Call(0)]);
+// Turns the calls in tail position of a function body into tail calls, that reuse the frame of the function.
+// A call is in tail position when it ends the body, possibly by jumping to its end from a match arm.
+fn tail_calls(mut instructions: Vec<OpCode>) -> Vec<OpCode> {
+    for idx in 0..instructions.len() {
+        if let OpCode::Call(argc) = instructions[idx] {
+            let mut next = idx + 1;
+            while let Some(OpCode::Jump(offset)) = instructions.get(next) {
+                next += *offset as usize + 1;
+            }
+            if next == instructions.len() {
+                instructions[idx] = OpCode::TailCall(argc);
+            }
         }
+    }
+    instructions
+}
 
-        Ok((self.output.clone(), symbols, self.constructors.clone(), self.macros.clone()))
+// The identity of a file, that does not depend on the path it is loaded through.
+fn identity(path: &str) -> String {
+    if library::read(path).is_some() {
+        path.to_string()
+    } else {
+        fs::canonicalize(path).map_or(path.to_string(), |p| p.to_string_lossy().to_string())
     }
 }
 
+// A file path relative to the working directory, if it is in it.
+pub fn relative(path: &str) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|cwd| Path::new(path).strip_prefix(cwd).ok().map(|p| p.to_string_lossy().to_string()))
+        .unwrap_or_else(|| path.to_string())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::bytecode::Capture;
+
+    type Compiled = (Bytecode, Vec<(String, bool)>, Vec<String>, Vec<(String, Macro)>);
+
+    // The compiler of `code` as the file `file`, that loads `prelude` first if one is given and optimizes if `optimize` is set.
+    fn compiler(code: &str, file: &str, prelude: Option<&str>, optimize: bool) -> Result<Compiler> {
+        let tokens = Lexer::new(code, file).proc_tokens()?;
+        let ast = Parser::new(tokens, file).parse()?;
+        Ok(Compiler::new(ast, file, Bytecode::new(), vec![], false, "".to_string(), false, vec![])?
+            .with_prelude(prelude.map(str::to_string))
+            .with_optimizations(optimize))
+    }
+    fn compile(code: &str) -> Result<Compiled> {
+        compiler(code, "TEST", None, false)?.compile(vec![])
+    }
 
     #[test]
     fn def() -> Result<()> {
-        let tokens = Lexer::new("(def a 42)(def 'impure b 34)", 0).proc_tokens()?;
-        let ast = Parser::new(tokens, "TEST").parse()?;
-        let (bcode, symbols, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![])?.compile(vec![])?;
-        assert_eq!(bcode.instructions, vec![OpCode::Def(0, 1),OpCode::LoadConst(0),  OpCode::Def(1, 1), OpCode::LoadConst(1)]);
+        let (bcode, symbols, ..) = compile("(def a 42)(def 'impure b 34)")?;
+        assert_eq!(bcode.instructions, vec![OpCode::LoadConst(0), OpCode::Def(0), OpCode::LoadConst(1), OpCode::Def(1)]);
         assert_eq!(symbols, vec![("a".to_string(), false), ("b".to_string(), true)]);
         Ok(())
     }
+
+    #[test]
+    fn macro_errors() -> Result<()> {
+        let error = |code: &str| compile(code).err().map(|e| (e.1.unwrap(), e.2));
+        assert_eq!(error("(macro id (x) x)\n\n(id\n  undefined)"), Some((4, "Variable not in scope: undefined.".to_string())));
+        assert_eq!(error("(macro id (x) x)\n(id 1 2)"), Some((2, "Expected 1 arguments, found 2.".to_string())));
+        assert_eq!(error("(macro loop (x) (loop x))\n(loop 1)"), Some((2, "Maximum macro expansion depth exceeded while expanding loop.".to_string())));
+        assert_eq!(error("(defmacro f () (λ (x) x))\n(f)"), Some((2, "Cannot convert a Function to code.".to_string())));
+        assert_eq!(error("(macro bad (& rest) rest)\n(bad 1)"), Some((2, "Variadic macro parameter used outside of an argument list: rest.".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn interpolation_errors() -> Result<()> {
+        let error = |code: &str| compile(code).err().map(|e| (e.1.unwrap(), e.2));
+        assert_eq!(error("\"first line\n${\n  undefined}\""), Some((3, "Variable not in scope: undefined.".to_string())));
+        assert_eq!(error("\"${1 2}\""), Some((1, "Expected exactly one expression in string interpolation.".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn scopes() -> Result<()> {
+        let error = |code: &str| compile(code).err().map(|e| (e.1.unwrap(), e.2));
+
+        assert_eq!(error("(def f (λ (x) x))\nx"), Some((2, "Variable not in scope: x (only bound in the function at line 1).".to_string())));
+        assert_eq!(error("(match 1\n  (y y))\n(def z y)"), Some((3, "Variable not in scope: y (only bound in the match arm at line 2).".to_string())));
+        assert_eq!(error("(begin (def a 1) a)\na"), Some((2, "Variable not in scope: a (only bound in the begin block at line 1).".to_string())));
+        assert_eq!(error("(λ (x y x) x)"), Some((1, "Parameter x is bound twice.".to_string())));
+        assert_eq!(error("(def f (λ () (begin (def 'impure g 1) (def h g) h)))"), Some((1, "Impure function used out of an `impure` declaration: g".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn slots() -> Result<()> {
+        let (bytecode, ..) = compile("(def f (λ (x y) (λ (x) (, x y))))")?;
+        assert_eq!((bytecode.chunks[0].arity, bytecode.chunks[0].slots), (1, 1));
+        assert_eq!(bytecode.chunks[0].instructions[..2], [OpCode::LoadLocal(0), OpCode::LoadUpvalue(0)]);
+        assert_eq!((bytecode.chunks[1].arity, bytecode.chunks[1].slots), (2, 2));
+        // Functions capture their free variables only, through the functions in between.
+        assert_eq!(bytecode.chunks[0].captures[..], [Capture::Local(1)]);
+        let (bytecode, ..) = compile("(def f (λ (a b) (λ () (λ () (, b b)))))")?;
+        assert_eq!(bytecode.chunks[0].captures[..], [Capture::Upvalue(0)]);
+        assert_eq!(bytecode.chunks[1].captures[..], [Capture::Local(1)]);
+        assert!(bytecode.chunks[2].captures.is_empty());
+        // Match arms bind their variables in the frame of the function.
+        let (bytecode, ..) = compile("(def g 1) (def f (λ (x) (match x ((, a b) (, b g)))))")?;
+        assert_eq!(bytecode.chunks[0].slots, 3);
+        assert_eq!(bytecode.chunks[0].instructions[2..], [OpCode::LoadLocal(2), OpCode::LoadGlobal(0), OpCode::Tuple(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn tail_calls() -> Result<()> {
+        let calls = |instructions: &[OpCode]| instructions.iter().filter_map(|i| match i {
+            OpCode::Call(_) => Some(false),
+            OpCode::TailCall(_) => Some(true),
+            _ => None,
+        }).collect::<Vec<bool>>();
+        let (bytecode, ..) = compile("(def f (λ (g n) (match n (0 (g (g 0))) (_ (f g (g n))))))")?;
+        assert_eq!(calls(&bytecode.chunks[0].instructions), vec![false, true, false, true]);
+        // The last call of a begin block is in tail position, as the call to the block is.
+        let (bytecode, ..) = compile("(def f (λ (g) (begin (def x (g 1)) (g x))))")?;
+        assert_eq!(calls(&bytecode.chunks[0].instructions), vec![false, true]);
+        assert_eq!(calls(&bytecode.chunks[1].instructions), vec![true]);
+        // The top-level calls are not.
+        let (bytecode, ..) = compile("(def g (λ (x) x)) (g 1)")?;
+        assert_eq!(calls(&bytecode.instructions), vec![false]);
+        Ok(())
+    }
+
+    #[test]
+    fn optimizations() -> Result<()> {
+        let compile = |code: &str, optimize: bool| {
+            let code = format!("(enum Maybe (Just x) Nothing) (def plus (λ (a b) (add a b))) {}", code);
+            compiler(&code, "TEST", None, optimize)?.compile(vec![])
+        };
+        let (bytecode, ..) = compile("(def x (plus 1 (plus 2 (neg 3))))", true)?;
+        assert_eq!(bytecode.instructions[2..], [OpCode::LoadConst(0), OpCode::Def(3)]);
+        assert_eq!(bytecode.constants[0], Literal::Integer(0));
+        let (bytecode, ..) = compile("(def x (plus 1 (plus 2 (neg 3))))", false)?;
+        assert!(bytecode.instructions.contains(&OpCode::Call(2)));
+        // A shadowed wrapper is called.
+        let (bytecode, ..) = compile("(def f (λ (plus) (plus 1 2)))", true)?;
+        assert_eq!(bytecode.chunks[1].instructions[3], OpCode::TailCall(2));
+        let (bytecode, ..) = compile("(def f (λ (x) (plus x 1)))", true)?;
+        assert_eq!(bytecode.chunks[1].instructions[..], [OpCode::LoadLocal(0), OpCode::LoadConst(0), OpCode::Builtin(0, 2)]);
+        // Begin blocks take slots in the frame of the enclosing function.
+        let (bytecode, ..) = compile("(def f (λ (a) (begin (def b a) b)))", true)?;
+        assert_eq!(bytecode.chunks.len(), 2);
+        assert_eq!((bytecode.chunks[1].slots, &bytecode.chunks[1].instructions[..]), (2, &[OpCode::LoadLocal(0), OpCode::DefLocal(1), OpCode::LoadLocal(1)][..]));
+        // Matches on known values keep the arms that can match.
+        let (bytecode, ..) = compile("(def x (match (Just 4) (Nothing 0) ((Just 3) 1) ((Just _) 2) (_ 3)))", true)?;
+        assert_eq!((&bytecode.instructions[2..], &bytecode.constants[..]), (&[OpCode::LoadConst(0), OpCode::Def(3)][..], &[Literal::Integer(2)][..]));
+        let (bytecode, ..) = compile("(def x (match (Just 4) (Nothing 0) ((Just n) n)))", true)?;
+        assert_eq!(bytecode.matches[0].arms.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn modules() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("orion-modules-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("geometry.orn", "(module geometry (export area Shape))
+            (enum Shape (Square side) (Rect w h))
+            (enum Unit Cm)
+            (def helper (λ (x) (mul x x)))
+            (def area (λ (s) (match s ((Square c) (helper c)) ((Rect w h) (mul w h)))))"),
+            ("other.orn", "(module other (export map)) (def map (λ (x) (add x 1)))"),
+            ("bad.orn", "(module bad (export missing))"),
+            ("misnamed.orn", "(module notit (export))"),
+            ("late.orn", "(def x 1) (module late (export))"),
+            ("forward.orn", "(module forward (export f)) (def f (λ (x) (g x))) (def g (λ (x) (add x 1)))"),
+            ("scaled.orn", "(module scaled (export scale)) (def scale (λ (x) (* x (length [1 2]))))"),
+        ];
+        for (name, content) in files.iter() {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let paths = vec![dir.to_str().unwrap().to_string()];
+        let compile = |code: &str, prelude: Option<&str>| compiler(code, "TEST", prelude, false)?.with_paths(paths.clone()).compile(vec![]);
+        let run = |code: &str, prelude: Option<&str>| -> Result<String> {
+            let (bytecode, ..) = compile(code, prelude)?;
+            let mut vm = VM::<256>::new(bytecode);
+            vm.eval(vec![], false)?;
+            let top = vm.pop()?;
+            Ok(vm.display_value(top, true))
+        };
+        let error = |code: &str| compile(code, None).err().map(|e| e.2);
+
+        assert_eq!(run("(import geometry :as g) (g.area (Square 3))", None)?, "9");
+        assert_eq!(run("(import geometry :as g) (, (geometry.area (g.Rect 2 3)) (match (geometry.Square 2) ((g.Square n) n)))", None)?, "(6 2)");
+        assert_eq!(error("(import geometry) geometry.Cm"), Some("Cm is not exported by module geometry.".to_string()));
+        assert_eq!(run("(import geometry (only area)) (area (Rect 2 5))", None)?, "10");
+        assert_eq!(run("(import forward) (forward.f 1)", None)?, "2");
+        assert_eq!(run("(import geometry) (import other :as o) (def map (λ (x) x)) (, (o.map 1) (map 1) (geometry.area (Square 2)))", None)?, "(2 1 4)");
+        assert_eq!(error("(import geometry) (geometry.helper 2)"), Some("helper is not exported by module geometry.".to_string()));
+        assert_eq!(error("(import geometry) (helper 2)"), Some("Variable not in scope: helper.".to_string()));
+        assert_eq!(error("(import geometry) Cm"), Some("Enum variant Cm does not exist.".to_string()));
+        assert_eq!(error("(import geometry (only helper))"), Some("helper is not exported by module geometry.".to_string()));
+        assert_eq!(error("(import bad)"), Some("Module bad exports missing, that it does not define.".to_string()));
+        assert!(error("(import misnamed)").unwrap().ends_with("does not declare module misnamed."));
+        assert_eq!(error("(load \"late.orn\")"), Some("A module declaration has to be the first expression of a loaded file.".to_string()));
+
+        // Modules see the prelude.
+        assert_eq!(run("(import scaled) (scaled.scale 3)", Some("prelude.orn"))?, "6");
+
+        // A module imported several times is compiled once.
+        let (bytecode, ..) = compile("(load \"geometry.orn\") (import geometry :as g) (import geometry)", None)?;
+        let area = bytecode.symbols.iter().position(|s| s == "geometry.area").unwrap() as u16;
+        assert_eq!(bytecode.instructions.iter().filter(|i| matches!(i, OpCode::Def(id) if *id == area)).count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn load_paths() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("orion-paths-{}", std::process::id()));
+        fs::create_dir_all(dir.join("project")).unwrap();
+        fs::create_dir_all(dir.join("search")).unwrap();
+        fs::write(dir.join("project/util.orn"), "(load \"helper.orn\") (def util (add helper 1))").unwrap();
+        fs::write(dir.join("project/helper.orn"), "(def helper 1)").unwrap();
+        fs::write(dir.join("search/extra.orn"), "(def extra 3)").unwrap();
+        let main = dir.join("project/main.orn").to_str().unwrap().to_string();
+        let search = dir.join("search").to_str().unwrap().to_string();
+        let compile = |code: &str| compiler(code, &main, None, false)?.with_paths(vec![search.clone()]).compile(vec![]);
+
+        // Loads are resolved from the directory of the loading file, whatever the working directory.
+        let (bytecode, ..) = compile("(load \"util.orn\" \"extra.orn\") (, util extra)")?;
+        assert!(bytecode.symbols.iter().any(|s| s == "helper"));
+        assert_eq!(
+            compile("(load \"missing.orn\")").err().map(|e| e.2),
+            Some(format!("File not found: missing.orn, searched in: {}, {}, <std>.", dir.join("project").to_str().unwrap(), search))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn load_graph() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("orion-graph-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let dir = fs::canonicalize(dir).unwrap();
+        let files = [
+            ("a.orn", "(load \"b.orn\")"),
+            ("b.orn", "(load \"sub/c.orn\")"),
+            ("sub/c.orn", "(load \"../a.orn\")"),
+            ("left.orn", "(load \"shared.orn\")"),
+            ("right.orn", "(load \"sub/../shared.orn\")"),
+            ("shared.orn", "(enum Color Red Green)"),
+        ];
+        for (name, content) in files.iter() {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let main = dir.join("main.orn").to_str().unwrap().to_string();
+        let compile = |code: &str| {
+            let mut compiler = compiler(code, &main, None, false)?;
+            compiler.compile(vec![])?;
+            Ok(compiler.dependencies().to_vec())
+        };
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        // A file reached through different paths is compiled once.
+        let graph = compile("(load \"left.orn\" \"right.orn\") Red")?;
+        assert_eq!(graph, vec![
+            (path("main.orn"), vec![path("left.orn"), path("right.orn")]),
+            (path("left.orn"), vec![path("shared.orn")]),
+            (path("shared.orn"), vec![]),
+            (path("right.orn"), vec![path("shared.orn")]),
+        ]);
+        assert_eq!(
+            compile("(load \"a.orn\")").err().map(|e| e.2),
+            Some(format!("Circular load: {} -> {} -> {} -> {}.", path("a.orn"), path("b.orn"), path("sub/c.orn"), path("a.orn")))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn prelude() -> Result<()> {
+        let compile = |code: &str, prelude: Option<&str>| compiler(code, "TEST", prelude, false)?.compile(vec![]);
+        // Without a library folder, the embedded standard library is loaded.
+        let (bytecode, ..) = compile("(length (range 0 3))", Some("prelude.orn"))?;
+        assert!(bytecode.symbols.iter().any(|s| s == "list.map"));
+        assert_eq!(compile("(length (range 0 3))", None).err().map(|e| e.2), Some("Variable not in scope: length.".to_string()));
+        let (bytecode, ..) = compile("(+ 1 2)", Some("math.orn"))?;
+        assert!(!bytecode.symbols.iter().any(|s| s == "list.map"));
+        // A prelude.orn next to the compiled file does not replace the one of the library.
+        let dir = std::env::temp_dir().join(format!("orion-prelude-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prelude.orn"), "(def local 1)").unwrap();
+        let file = dir.join("main.orn").to_string_lossy().to_string();
+        let (bytecode, ..) = compiler("(+ 1 2)", &file, Some("prelude.orn"), false)?.compile(vec![])?;
+        assert!(bytecode.symbols.iter().any(|s| s == "list.map") && !bytecode.symbols.iter().any(|s| s == "local"));
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn exhaustiveness() -> Result<()> {
+        let mut compiler = compiler("(enum Maybe (Just x) Nothing)
+        (def f (λ (m) (match m ((Just x) x))))
+        (def g (λ (m) (match m (_ 0) (Nothing 1))))
+        (def h (λ (m) (match (, m 0) ((, (Just _) _) 0) ((, Nothing 0) 1))))", "TEST", None, false)?;
+        compiler.compile(vec![])?;
+        let warnings = compiler.warnings.into_iter().map(|w| (w.1.unwrap(), w.2)).collect::<Vec<_>>();
+        assert_eq!(warnings, vec![
+                   (2, "Non-exhaustive patterns, values not covered: Nothing.".to_string()),
+                   (3, "Unreachable pattern: Nothing.".to_string()),
+                   (4, "Non-exhaustive patterns, values not covered: (, Nothing 1).".to_string())]);
+        Ok(())
+    }
+
+    #[test]
+    fn literal_exhaustiveness() -> Result<()> {
+        let mut compiler = compiler("(def a (λ (n) (match n ((.. 0 2000000000) 1) (_ 0))))
+        (def b (λ (n) (match n ((.. 0 2147483647) 1))))
+        (def c (λ (n) (match n (0 1) ((.. 1 5) 2) (7 3))))
+        (def d (λ (s) (match s ((++ \"a\" \"b\") 0) ((++ \"a\" r) 1) (_ 2))))
+        (def e (λ (s) (match s (_ 0) ((++ \"a\" r) 1))))", "TEST", None, false)?;
+        compiler.compile(vec![])?;
+        let warnings = compiler.warnings.into_iter().map(|w| (w.1.unwrap(), w.2)).collect::<Vec<_>>();
+        assert_eq!(warnings, vec![
+                   (2, "Non-exhaustive patterns, values not covered: -1.".to_string()),
+                   (3, "Non-exhaustive patterns, values not covered: 6.".to_string()),
+                   (5, "Unreachable pattern: (++ \"a\" _).".to_string())]);
+        Ok(())
+    }
 }
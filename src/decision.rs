@@ -0,0 +1,235 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{
+    bug,
+    bytecode::{Decision, Slot, Test},
+    parser::Literal,
+};
+
+#[derive(Clone, Debug)]
+pub enum TreePattern {
+    Any,
+    Bind(u16, Box<TreePattern>), // (slot, pattern)
+    Constr(u16, Vec<TreePattern>), // (constr_id, [pattern])
+    Tuple(Vec<TreePattern>),
+    Literal(u16, Literal), // (const_id, value)
+    Range(u16, u16, Literal, Literal), // (start_const_id, end_const_id, start, end)
+    Prefix(u16, Box<TreePattern>), // (const_id, rest)
+    Pin(Slot), // (variable)
+    Or(Vec<TreePattern>),
+}
+
+#[derive(Clone)]
+struct Row {
+    columns: Vec<(u16, TreePattern)>, // (occurrence, pattern)
+    bindings: Vec<(u16, u16)>, // (slot, occurrence)
+    arm: u16,
+}
+
+// Whether the tests of a pattern are mutually exclusive with the tests of different patterns
+// of the same kind, so that they can be grouped in a single switch.
+fn is_exclusive(pat: &TreePattern) -> bool {
+    matches!(pat, TreePattern::Constr(..) | TreePattern::Tuple(_) | TreePattern::Literal(..))
+}
+
+fn test_of(pat: &TreePattern) -> (Test, u16) {
+    match pat {
+        TreePattern::Constr(id, args) => (Test::Constr(*id), args.len() as u16),
+        TreePattern::Tuple(args) => (Test::Tuple(args.len() as u16), args.len() as u16),
+        TreePattern::Literal(id, _) => (Test::Literal(*id), 0),
+        TreePattern::Range(start, end, ..) => (Test::Range(*start, *end), 0),
+        TreePattern::Prefix(id, _) => (Test::Prefix(*id), 1),
+        TreePattern::Pin(sym) => (Test::Pin(*sym), 0),
+        _ => bug!("UNEXPECTED_IRREFUTABLE_PATTERN"),
+    }
+}
+
+fn in_range(lit: &Literal, start: &Literal, end: &Literal) -> bool {
+    match (lit, start, end) {
+        (Literal::Integer(i), Literal::Integer(start), Literal::Integer(end)) => (start..=end).contains(&i),
+        (Literal::Single(s), Literal::Single(start), Literal::Single(end)) => (start..=end).contains(&s),
+        _ => false,
+    }
+}
+
+impl Row {
+    fn column(&self, occurrence: u16) -> Option<usize> {
+        self.columns.iter().position(|(occ, _)| *occ == occurrence)
+    }
+    // Strips the variables, wildcards and as-patterns from the columns, and expands the or-patterns.
+    fn normalize(mut self) -> Vec<Row> {
+        let pos = match self.columns.iter().position(|(_, pat)| matches!(pat, TreePattern::Any | TreePattern::Bind(..) | TreePattern::Or(_))) {
+            Some(pos) => pos,
+            None => return vec![self],
+        };
+        let (occurrence, pat) = self.columns.remove(pos);
+        match pat {
+            TreePattern::Bind(sym, pat) => {
+                self.bindings.push((sym, occurrence));
+                self.columns.insert(pos, (occurrence, *pat));
+                self.normalize()
+            }
+            TreePattern::Or(alternatives) => alternatives.into_iter().flat_map(|alt| {
+                let mut row = self.clone();
+                row.columns.insert(pos, (occurrence, alt));
+                row.normalize()
+            }).collect(),
+            _ => self.normalize(),
+        }
+    }
+    // Replaces the column by the sub-patterns of the pattern, stored in the occurrences following `child`.
+    fn expand(&self, pos: usize, child: u16) -> Row {
+        let mut row = self.clone();
+        let (_, pat) = row.columns.remove(pos);
+        let inside = match pat {
+            TreePattern::Constr(_, args) | TreePattern::Tuple(args) => args,
+            TreePattern::Prefix(_, rest) => vec![*rest],
+            _ => vec![],
+        };
+        row.columns.extend(inside.into_iter().enumerate().map(|(idx, pat)| (child + idx as u16, pat)));
+        row
+    }
+}
+
+pub struct Builder {
+    occurrences: u16,
+}
+
+impl Builder {
+    // Builds the decision tree of a `match` from its arms' patterns, returns it and the amount of occurrences it uses.
+    pub fn build(patterns: Vec<TreePattern>) -> (Decision, u16) {
+        let rows = patterns.into_iter().enumerate().map(|(arm, pat)| Row {
+            columns: vec![(0, pat)],
+            bindings: vec![],
+            arm: arm as u16,
+        }).collect();
+        let mut builder = Self { occurrences: 1 };
+        let tree = builder.compile(rows);
+        (tree, builder.occurrences)
+    }
+    fn alloc(&mut self, amount: u16) -> u16 {
+        self.occurrences += amount;
+        self.occurrences - amount
+    }
+    fn compile(&mut self, rows: Vec<Row>) -> Decision {
+        let rows = rows.into_iter().flat_map(Row::normalize).collect::<Vec<Row>>();
+        let first = match rows.first() {
+            Some(row) => row,
+            None => return Decision::Fail,
+        };
+        let (occurrence, head) = match first.columns.first() {
+            Some(column) => column.clone(),
+            None => return Decision::Leaf(first.arm, first.bindings.clone()),
+        };
+
+        if is_exclusive(&head) {
+            let mut tests: Vec<(Test, u16, Option<Literal>)> = vec![];
+            for row in &rows {
+                if let Some(pos) = row.column(occurrence) {
+                    let pat = &row.columns[pos].1;
+                    if is_exclusive(pat) {
+                        let (test, arity) = test_of(pat);
+                        if !tests.iter().any(|(t, ..)| t == &test) {
+                            let lit = if let TreePattern::Literal(_, lit) = pat { Some(lit.clone()) } else { None };
+                            tests.push((test, arity, lit));
+                        }
+                    }
+                }
+            }
+            let cases = tests.into_iter().map(|(test, arity, lit)| {
+                let child = self.alloc(arity);
+                let specialized = rows.iter().filter_map(|row| {
+                    let pos = match row.column(occurrence) {
+                        Some(pos) => pos,
+                        None => return Some(row.clone()),
+                    };
+                    match &row.columns[pos].1 {
+                        pat if is_exclusive(pat) => if test_of(pat).0 == test {
+                            Some(row.expand(pos, child))
+                        } else {
+                            None
+                        }
+                        TreePattern::Range(_, _, start, end) => match &lit {
+                            Some(lit) if in_range(lit, start, end) => Some(row.expand(pos, child)),
+                            _ => None,
+                        }
+                        _ => Some(row.clone()), // Pins and prefixes are tested later on.
+                    }
+                }).collect();
+                (test, child, self.compile(specialized))
+            }).collect();
+            let default = rows.iter().filter(|row| match row.column(occurrence) {
+                Some(pos) => !is_exclusive(&row.columns[pos].1),
+                None => true,
+            }).cloned().collect();
+            Decision::Switch(occurrence, cases, Box::new(self.compile(default)))
+        } else {
+            let (test, arity) = test_of(&head);
+            let child = self.alloc(arity);
+            let (mut success, mut failure) = (vec![], vec![]);
+            for row in &rows {
+                match row.column(occurrence) {
+                    Some(pos) if test_of(&row.columns[pos].1).0 == test => success.push(row.expand(pos, child)),
+                    _ => {
+                        success.push(row.clone());
+                        failure.push(row.clone());
+                    }
+                }
+            }
+            Decision::Switch(occurrence, vec![(test, child, self.compile(success))], Box::new(self.compile(failure)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn switch() {
+        // (match x ((Just 1) a) ((Just y) b) (_ c))
+        let (tree, occurrences) = Builder::build(vec![
+            TreePattern::Constr(0, vec![TreePattern::Literal(0, Literal::Integer(1))]),
+            TreePattern::Constr(0, vec![TreePattern::Bind(0, Box::new(TreePattern::Any))]),
+            TreePattern::Any,
+        ]);
+        assert_eq!(occurrences, 2);
+        assert_eq!(tree, Decision::Switch(0, vec![
+            (Test::Constr(0), 1, Decision::Switch(1, vec![
+                (Test::Literal(0), 2, Decision::Leaf(0, vec![])),
+            ], Box::new(Decision::Leaf(1, vec![(0, 1)])))),
+        ], Box::new(Decision::Leaf(2, vec![]))));
+    }
+
+    #[test]
+    fn or_pattern() {
+        // (match x ((| 1 2) a) (3 b))
+        let (tree, _) = Builder::build(vec![
+            TreePattern::Or(vec![TreePattern::Literal(0, Literal::Integer(1)), TreePattern::Literal(1, Literal::Integer(2))]),
+            TreePattern::Literal(2, Literal::Integer(3)),
+        ]);
+        assert_eq!(tree, Decision::Switch(0, vec![
+            (Test::Literal(0), 1, Decision::Leaf(0, vec![])),
+            (Test::Literal(1), 1, Decision::Leaf(0, vec![])),
+            (Test::Literal(2), 1, Decision::Leaf(1, vec![])),
+        ], Box::new(Decision::Fail)));
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::parser::Literal;
+
+// Maximum amount of uncovered values reported for a single `match`.
+const MAX_WITNESSES: usize = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pat {
+    Any,
+    Constr(u16, Vec<Pat>), // (constr_id, [pat])
+    Tuple(Vec<Pat>),
+    Literal(Literal),
+    Range(Literal, Literal),
+    Opaque(String), // Patterns whose matched values cannot be known at compile time (pins).
+    Prefix(String), // String prefixes, whose rest patterns are not analysed, so that only wildcards cover them.
+    Or(Vec<Pat>),
+}
+
+enum Ctor {
+    Constr(u16, usize), // (constr_id, arity)
+    Tuple(usize),
+    Literal(Literal),
+    Range(Literal, Literal),
+    Opaque(String),
+}
+
+enum Kind {
+    Enum(u16, u16), // (first_constr_id, last_constr_id)
+    Tuple(usize),
+    Other,
+}
+
+pub struct Matrix<'a> {
+    pub types: &'a [(String, u16, u16)],
+    pub constructors: &'a [(u8, u16)],
+    pub names: &'a [String],
+}
+
+fn in_range(lit: &Literal, start: &Literal, end: &Literal) -> bool {
+    match (lit, start, end) {
+        (Literal::Integer(i), Literal::Integer(start), Literal::Integer(end)) => (start..=end).contains(&i),
+        (Literal::Single(s), Literal::Single(start), Literal::Single(end)) => (start..=end).contains(&s),
+        _ => false,
+    }
+}
+
+// Replaces every row starting with an or-pattern by one row per alternative.
+fn expand(rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    rows.iter().flat_map(|row| match row.first() {
+        Some(Pat::Or(alternatives)) => expand(&alternatives.iter().map(|alt| {
+            let mut new_row = vec![alt.clone()];
+            new_row.extend_from_slice(&row[1..]);
+            new_row
+        }).collect::<Vec<_>>()),
+        _ => vec![row.clone()],
+    }).collect()
+}
+
+// Keeps the rows starting with a wildcard, without their first column.
+fn default(rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    rows.iter().filter(|row| row[0] == Pat::Any).map(|row| row[1..].to_vec()).collect()
+}
+
+fn specialize(rows: &[Vec<Pat>], ctor: &Ctor) -> Vec<Vec<Pat>> {
+    let arity = match ctor {
+        Ctor::Constr(_, arity) | Ctor::Tuple(arity) => *arity,
+        _ => 0,
+    };
+    rows.iter().filter_map(|row| {
+        let args = match (&row[0], ctor) {
+            (Pat::Any, _) => vec![Pat::Any; arity],
+            (Pat::Constr(id, args), Ctor::Constr(c, _)) if id == c => args.clone(),
+            (Pat::Tuple(args), Ctor::Tuple(n)) if args.len() == *n => args.clone(),
+            (Pat::Literal(lit), Ctor::Literal(l)) if lit == l => vec![],
+            (Pat::Range(start, end), Ctor::Literal(l)) if in_range(l, start, end) => vec![],
+            (Pat::Range(start, end), Ctor::Range(s, e)) if in_range(s, start, end) && in_range(e, start, end) => vec![],
+            (Pat::Opaque(key), Ctor::Opaque(k)) if key == k => vec![],
+            _ => return None,
+        };
+        let mut new_row = args;
+        new_row.extend_from_slice(&row[1..]);
+        Some(new_row)
+    }).collect()
+}
+
+impl<'a> Matrix<'a> {
+    fn arity(&self, constr: u16) -> usize {
+        self.constructors[constr as usize].0 as usize
+    }
+    fn kind(&self, rows: &[Vec<Pat>]) -> Kind {
+        for row in rows {
+            match &row[0] {
+                Pat::Constr(id, _) => if let Some((_, start, end)) = self.types.iter().find(|(_, start, end)| (start..=end).contains(&id)) {
+                    return Kind::Enum(*start, *end);
+                }
+                Pat::Tuple(args) => return Kind::Tuple(args.len()),
+                _ => {}
+            }
+        }
+        Kind::Other
+    }
+    fn used_constructors(&self, rows: &[Vec<Pat>]) -> Vec<u16> {
+        rows.iter().filter_map(|row| match &row[0] {
+            Pat::Constr(id, _) => Some(*id),
+            _ => None,
+        }).collect()
+    }
+    // Checks whether a row matching `q` would match values that no row of `rows` matches.
+    pub fn useful(&self, rows: &[Vec<Pat>], q: &[Pat]) -> bool {
+        if q.is_empty() {
+            return rows.is_empty();
+        }
+        let rows = expand(rows);
+        let rest = &q[1..];
+        let specialized = |ctor: Ctor, args: Vec<Pat>| {
+            let mut new_q = args;
+            new_q.extend_from_slice(rest);
+            self.useful(&specialize(&rows, &ctor), &new_q)
+        };
+        match &q[0] {
+            Pat::Or(alternatives) => alternatives.iter().any(|alt| {
+                let mut new_q = vec![alt.clone()];
+                new_q.extend_from_slice(rest);
+                self.useful(&rows, &new_q)
+            }),
+            Pat::Any => match self.kind(&rows) {
+                Kind::Enum(start, end) if (start..=end).all(|c| self.used_constructors(&rows).contains(&c)) => {
+                    (start..=end).any(|c| specialized(Ctor::Constr(c, self.arity(c)), vec![Pat::Any; self.arity(c)]))
+                }
+                Kind::Tuple(n) => specialized(Ctor::Tuple(n), vec![Pat::Any; n]),
+                _ => self.useful(&default(&rows), rest),
+            }
+            Pat::Constr(id, args) => specialized(Ctor::Constr(*id, args.len()), args.clone()),
+            Pat::Tuple(args) => specialized(Ctor::Tuple(args.len()), args.clone()),
+            Pat::Literal(lit) => specialized(Ctor::Literal(lit.clone()), vec![]),
+            Pat::Range(start, end) => specialized(Ctor::Range(start.clone(), end.clone()), vec![]),
+            Pat::Opaque(key) => specialized(Ctor::Opaque(key.clone()), vec![]),
+            Pat::Prefix(_) => self.useful(&default(&rows), rest),
+        }
+    }
+    // Returns examples of value vectors of length `n` that are matched by no row of `rows`.
+    pub fn missing(&self, rows: &[Vec<Pat>], n: usize) -> Vec<Vec<Pat>> {
+        if n == 0 {
+            return if rows.is_empty() { vec![vec![]] } else { vec![] };
+        }
+        let rows = expand(rows);
+        let rebuild = |witnesses: Vec<Vec<Pat>>, arity: usize, head: &dyn Fn(Vec<Pat>) -> Pat| {
+            witnesses.into_iter().map(|w| {
+                let mut new_w = vec![head(w[..arity].to_vec())];
+                new_w.extend_from_slice(&w[arity..]);
+                new_w
+            }).collect::<Vec<_>>()
+        };
+        let with_heads = |heads: Vec<Pat>, rest: Vec<Vec<Pat>>| {
+            heads.into_iter().flat_map(|head| rest.iter().map(move |w| {
+                let mut new_w = vec![head.clone()];
+                new_w.extend_from_slice(w);
+                new_w
+            })).take(MAX_WITNESSES).collect::<Vec<_>>()
+        };
+        match self.kind(&rows) {
+            Kind::Enum(start, end) => {
+                let used = self.used_constructors(&rows);
+                if (start..=end).all(|c| used.contains(&c)) {
+                    (start..=end).flat_map(|c| {
+                        let arity = self.arity(c);
+                        let missing = self.missing(&specialize(&rows, &Ctor::Constr(c, arity)), arity + n - 1);
+                        rebuild(missing, arity, &|args| Pat::Constr(c, args))
+                    }).take(MAX_WITNESSES).collect()
+                } else {
+                    let rest = self.missing(&default(&rows), n - 1);
+                    let heads = (start..=end).filter(|c| !used.contains(c)).map(|c| Pat::Constr(c, vec![Pat::Any; self.arity(c)])).collect();
+                    with_heads(heads, rest)
+                }
+            }
+            Kind::Tuple(arity) => {
+                let missing = self.missing(&specialize(&rows, &Ctor::Tuple(arity)), arity + n - 1);
+                rebuild(missing, arity, &Pat::Tuple)
+            }
+            Kind::Other => {
+                let rest = self.missing(&default(&rows), n - 1);
+                if rest.is_empty() {
+                    return rest;
+                }
+                match self.uncovered_literal(&rows) {
+                    Some(head) => with_heads(vec![head], rest),
+                    None => vec![],
+                }
+            }
+        }
+    }
+    // Finds an Integer that is matched by no literal of the first column, if the column contains Integers: the smallest uncovered natural, or else the greatest uncovered negative Integer.
+    // `None` when they cover all the Integers.
+    fn uncovered_literal(&self, rows: &[Vec<Pat>]) -> Option<Pat> {
+        let integers = rows.iter().any(|row| matches!(&row[0], Pat::Literal(Literal::Integer(_)) | Pat::Range(Literal::Integer(_), _)));
+        if !integers {
+            return Some(Pat::Any);
+        }
+        let mut intervals = rows.iter().filter_map(|row| match &row[0] {
+            Pat::Literal(Literal::Integer(i)) => Some((*i, *i)),
+            Pat::Range(Literal::Integer(start), Literal::Integer(end)) if start <= end => Some((*start, *end)),
+            _ => None,
+        }).collect::<Vec<(i32, i32)>>();
+        // Sorted by start, an interval starting after the candidate means that none of the following ones covers it.
+        intervals.sort_unstable();
+        let mut candidate = Some(0);
+        for (start, end) in &intervals {
+            match candidate {
+                Some(c) if (*start..=*end).contains(&c) => candidate = end.checked_add(1),
+                _ => {}
+            }
+        }
+        if candidate.is_none() {
+            intervals.sort_unstable_by_key(|(_, end)| std::cmp::Reverse(*end));
+            candidate = Some(-1);
+            for (start, end) in &intervals {
+                match candidate {
+                    Some(c) if (*start..=*end).contains(&c) => candidate = start.checked_sub(1),
+                    _ => {}
+                }
+            }
+        }
+        candidate.map(|c| Pat::Literal(Literal::Integer(c)))
+    }
+    pub fn display(&self, pat: &Pat) -> String {
+        match pat {
+            Pat::Any | Pat::Opaque(_) => "_".to_string(),
+            Pat::Constr(id, args) => {
+                let name = &self.names[*id as usize];
+                if args.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("({} {})", name, args.iter().map(|a| self.display(a)).collect::<Vec<_>>().join(" "))
+                }
+            }
+            Pat::Tuple(args) => if args.is_empty() {
+                "()".to_string()
+            } else {
+                format!("(, {})", args.iter().map(|a| self.display(a)).collect::<Vec<_>>().join(" "))
+            }
+            Pat::Literal(Literal::Integer(i)) => format!("{}", i),
+            Pat::Literal(Literal::Single(s)) => format!("{}", s),
+            Pat::Literal(Literal::String(s)) => format!("{:?}", s),
+            Pat::Prefix(prefix) => format!("(++ {:?} _)", prefix),
+            Pat::Range(start, end) => format!("(.. {} {})", self.display(&Pat::Literal(start.clone())), self.display(&Pat::Literal(end.clone()))),
+            Pat::Or(alternatives) => format!("(| {})", alternatives.iter().map(|a| self.display(a)).collect::<Vec<_>>().join(" ")),
+        }
+    }
+}
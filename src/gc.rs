@@ -0,0 +1,104 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::vm::{Cell, Value};
+use std::{cell::RefCell, collections::HashSet, rc::{Rc, Weak}};
+
+// The amount of cells below which the collector is never run.
+const MIN_THRESHOLD: usize = 1024;
+
+// What is left after a collection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub environments: usize, // The live cells.
+    pub values: usize, // The values reachable from the roots.
+    pub collected: usize, // The cells freed.
+}
+
+// The values and the cells reachable from the roots.
+#[derive(Default)]
+struct Marks {
+    values: HashSet<*const Value>,
+    cells: HashSet<*const RefCell<Rc<Value>>>,
+}
+
+impl Marks {
+    // Walks the values with a stack of its own, as they can be nested deeper than the native one.
+    fn trace(&mut self, mut values: Vec<Rc<Value>>, mut cells: Vec<Cell>) {
+        loop {
+            if let Some(cell) = cells.pop() {
+                if self.cells.insert(Rc::as_ptr(&cell)) {
+                    values.push(cell.borrow().clone());
+                }
+            } else if let Some(value) = values.pop() {
+                if self.values.insert(Rc::as_ptr(&value)) {
+                    match &*value {
+                        Value::Lambda(_, upvalues) => cells.extend(upvalues.iter().cloned()),
+                        Value::Constructor(_, vals) | Value::Tuple(vals) => values.extend(vals.iter().cloned()),
+                        Value::Integer(_) | Value::Single(_) | Value::String(_) => {}
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+// The cells created by the VM. Reference counting frees the values and the environments of the closures once they are not used anymore,
+// except for the cycles made by the recursive local functions, whose cell holds a closure capturing it, that the collector breaks.
+#[derive(Debug, Default)]
+pub struct Heap {
+    cells: Vec<Weak<RefCell<Rc<Value>>>>,
+    threshold: usize,
+    pub collected: usize, // The cells freed by the collector so far.
+}
+
+impl Heap {
+    pub fn cell(&mut self, value: Rc<Value>) -> Cell {
+        let cell = Rc::new(RefCell::new(value));
+        self.cells.push(Rc::downgrade(&cell));
+        cell
+    }
+    // Whether enough cells were created since the last collection to run the collector again.
+    pub fn is_full(&self) -> bool {
+        self.cells.len() >= self.threshold.max(MIN_THRESHOLD)
+    }
+    // Empties the live cells that cannot be reached from the roots, which frees the cycles they are part of.
+    pub fn collect(&mut self, values: Vec<Rc<Value>>, cells: Vec<Cell>) -> Stats {
+        let mut marks = Marks::default();
+        marks.trace(values, cells);
+        let garbage = self.cells.iter().filter_map(Weak::upgrade).filter(|cell| !marks.cells.contains(&Rc::as_ptr(cell))).collect::<Vec<Cell>>();
+        let collected = garbage.len();
+        // Kept alive until all of them are emptied, so that none is freed while borrowed.
+        for cell in &garbage {
+            *cell.borrow_mut() = Rc::new(Value::Tuple(vec![]));
+        }
+        drop(garbage);
+        self.cells.retain(|weak| weak.strong_count() > 0);
+        self.threshold = self.cells.len() * 2;
+        self.collected += collected;
+        Stats {
+            environments: self.cells.len(),
+            values: marks.values.len(),
+            collected,
+        }
+    }
+}
@@ -25,7 +25,7 @@ impl <const STACK_SIZE: usize> VM<STACK_SIZE> {
     pub fn put_str(&mut self) -> Result<Rc<Value>> {
         let to_print = self.pop()?;
 
-        match (*to_print).clone() {
+        match &*to_print {
             Value::String(s) => {
                 print!("{}", s);
                 io::stdout().flush().unwrap();
@@ -37,7 +37,7 @@ impl <const STACK_SIZE: usize> VM<STACK_SIZE> {
     pub fn get_line(&mut self) -> Result<Rc<Value>> {
         let mut buffer = String::new();
         match io::stdin().read_line(&mut buffer) {
-            Ok(_) => Ok(Rc::new(Value::String(buffer.trim().to_string()))),
+            Ok(_) => Ok(Rc::new(Value::String(buffer.trim().into()))),
             Err(_) => error!(=> "Failed to get line from user input."),
         }
     }
@@ -29,17 +29,25 @@ pub enum TType {
     LBracket,
     RBracket,
     Macro,
+    DefMacro,
     Str(String),
     Number(i32),
     Float(f32),
     Ident(String),
     Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    Dispatch(char), // `#` followed by an opening delimiter, starting a reader macro.
+    Interpolation(usize), // Followed by the segments of an interpolated string, the expressions being in braces.
     Def,
     Enum,
     Tuple,
     Lambda,
     Match,
     Load,
+    Module,
+    Import,
     Begin,
 
     Builtin(String),
@@ -75,7 +83,7 @@ impl Token {
 }
 
 pub struct Lexer {
-    input: String,
+    input: Vec<char>, // Indexed by `current` and `start`, that count characters.
     output: Vec<Token>,
     current: usize,
     line: usize,
@@ -87,7 +95,7 @@ pub struct Lexer {
 impl Lexer {
     pub fn new(input: impl ToString, file: impl ToString) -> Self {
         Self {
-            input: input.to_string().replace("λ", "\\").to_string(),
+            input: input.to_string().replace("λ", "\\").chars().collect(),
             output: vec![],
             current: 0,
             line: 1,
@@ -108,22 +116,78 @@ impl Lexer {
         }
     }
     fn is_at_end(&self) -> bool {
-        self.current >= self.input.chars().count()
+        self.current >= self.input.len()
     }
     fn peek(&self) -> char {
-        self.input.chars().nth(self.current).unwrap()
+        self.input[self.current]
     }
     fn advance(&mut self) -> char {
         self.current += 1;
-        self.input.chars().nth(self.current - 1).unwrap()
+        self.input[self.current - 1]
     }
     fn add_token(&mut self, ttype: TType) {
         self.output.push(Token::new(ttype, self.line));
     }
+    fn peek_next(&self) -> Option<char> {
+        self.input.get(self.current + 1).copied()
+    }
+    fn text(&self, start: usize, end: usize) -> String {
+        self.input[start..end].iter().collect()
+    }
+    // Lexes the expression of a `${...}` interpolation, the opening `${` being consumed.
+    fn interpolation(&mut self) -> Result<Vec<Token>> {
+        let line = self.line;
+        let start = self.current;
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return error!(self.file, line => "Unterminated string interpolation.");
+            }
+            match self.advance() {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                '\n' => self.line += 1,
+                '"' => {
+                    while !self.is_at_end() && self.peek() != '"' {
+                        if self.advance() == '\n' {
+                            self.line += 1;
+                        }
+                    }
+                    if !self.is_at_end() {
+                        self.advance();
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut tokens = vec![Token::new(TType::LBrace, line)];
+        tokens.extend(Lexer::new(self.text(start, self.current - 1), &self.file).line(line).proc_tokens()?);
+        tokens.push(Token::new(TType::RBrace, self.line));
+        Ok(tokens)
+    }
     fn string(&mut self) -> Result<()> {
+        let line = self.line;
+        let mut segments = vec![];
+        let mut segment_start = self.start + 1; // Skip opening double quotes
         while !self.is_at_end() && self.peek() != '"' {
-            if self.peek() == '\n' {
-                self.line += 1;
+            match (self.peek(), self.peek_next()) {
+                ('\n', _) => self.line += 1,
+                // Escaped, so that `\\${` starts an interpolation after a backslash, and `\${` does not.
+                ('\\', Some('$')) | ('\\', Some('\\')) => {
+                    self.advance();
+                }
+                ('$', Some('{')) => {
+                    let text = apply_ansi_codes(&self.input[segment_start..self.current]);
+                    if !text.is_empty() {
+                        segments.push(vec![Token::new(TType::Str(text), self.line)]);
+                    }
+                    self.advance();
+                    self.advance();
+                    segments.push(self.interpolation()?);
+                    segment_start = self.current;
+                    continue;
+                }
+                _ => {}
             }
             self.advance();
         }
@@ -133,9 +197,18 @@ impl Lexer {
 
         self.advance(); // Closing double quotes
 
-        self.add_token(TType::Str(apply_ansi_codes(
-            &self.input[self.start + 1..self.current - 1], // Remove opening and closing double quotes
-        )));
+        let text = apply_ansi_codes(
+            &self.input[segment_start..self.current - 1], // Remove closing double quotes
+        );
+        if segments.is_empty() {
+            self.add_token(TType::Str(text));
+        } else {
+            if !text.is_empty() {
+                segments.push(vec![Token::new(TType::Str(text), self.line)]);
+            }
+            self.output.push(Token::new(TType::Interpolation(segments.len()), line));
+            self.output.extend(segments.into_iter().flatten());
+        }
 
         Ok(())
     }
@@ -152,6 +225,13 @@ impl Lexer {
             ' ' | '\r' | '\t' => {}
             '\n' => self.line += 1,
             '\'' => self.add_token(TType::Quote),
+            '`' => self.add_token(TType::Quasiquote),
+            '~' => if !self.is_at_end() && self.peek() == '@' {
+                self.advance();
+                self.add_token(TType::UnquoteSplicing)
+            } else {
+                self.add_token(TType::Unquote)
+            }
             '"' => self.string()?,
             '#' => {
                 if !self.is_at_end() && self.peek() == '!' && self.line == 1 {
@@ -169,6 +249,13 @@ impl Lexer {
                         }
                         self.advance();
                     }
+                } else if !self.is_at_end() && ['(', '[', '{', '"'].contains(&self.peek()) {
+                    let delimiter = self.advance();
+                    self.add_token(TType::Dispatch(delimiter));
+                    if delimiter == '"' {
+                        self.start = self.current - 1;
+                        self.string()?;
+                    }
                 } else {
                     self.identifier();
                 }
@@ -205,7 +292,7 @@ impl Lexer {
             self.advance();
         }
 
-        let raw = self.input[self.start..self.current].to_string();
+        let raw = self.text(self.start, self.current);
 
         match raw.parse::<i32>() {
             Ok(i) => self.add_token(TType::Number(i)),
@@ -213,13 +300,13 @@ impl Lexer {
         }
     }
     fn identifier(&mut self) {
-        let stop = vec!['(', ')', ' ', '\t', '\n', '\r'];
+        let stop = ['(', ')', '[', ']', '{', '}', ' ', '\t', '\n', '\r'];
 
         while !self.is_at_end() && !stop.contains(&self.peek()) {
             self.advance();
         }
 
-        let raw = self.input[self.start..self.current].to_string();
+        let raw = self.text(self.start, self.current);
 
         if self.builtins.contains(&raw) {
             self.add_token(TType::Builtin(raw));
@@ -227,11 +314,14 @@ impl Lexer {
             match raw.as_str() {
                 "def" => self.add_token(TType::Def),
                 "macro" => self.add_token(TType::Macro),
+                "defmacro" => self.add_token(TType::DefMacro),
                 "enum" => self.add_token(TType::Enum),
                 "\\" => self.add_token(TType::Lambda),
                 "," => self.add_token(TType::Tuple),
                 "match" => self.add_token(TType::Match),
                 "load" => self.add_token(TType::Load),
+                "module" => self.add_token(TType::Module),
+                "import" => self.add_token(TType::Import),
                 "begin" => self.add_token(TType::Begin),
                 _ => self.add_token(TType::Ident(raw)),
             }
@@ -249,7 +339,9 @@ impl Lexer {
 
         self.register_builtin("type");
         self.register_builtin("_cmp");
+        self.register_builtin("_append");
         self.register_builtin("panic");
+        self.register_builtin("eval");
 
         self.register_builtin("add");
         self.register_builtin("sub");
@@ -272,15 +364,25 @@ impl Lexer {
     }
 }
 
-fn apply_ansi_codes(input: &str) -> String {
-    input
-        .replace("\\x1b", "\x1b")
-        .replace("\\n", "\n")
-        .replace("\\r", "\r")
-        .replace("\\t", "\t")
-        .replace("\\0", "\0")
-        .replace("\\\\", "\\")
-        .to_string()
+// Replaces the escape sequences in a single pass, so that an escaped backslash does not escape what follows it.
+fn apply_ansi_codes(input: &[char]) -> String {
+    let mut output = String::new();
+    let mut idx = 0;
+    while idx < input.len() {
+        let (c, length) = match (input[idx], input.get(idx + 1)) {
+            ('\\', Some('n')) => ('\n', 2),
+            ('\\', Some('r')) => ('\r', 2),
+            ('\\', Some('t')) => ('\t', 2),
+            ('\\', Some('0')) => ('\0', 2),
+            ('\\', Some('$')) => ('$', 2),
+            ('\\', Some('\\')) => ('\\', 2),
+            ('\\', Some('x')) if input[idx + 2..].starts_with(&['1', 'b']) => ('\x1b', 4),
+            (c, _) => (c, 1),
+        };
+        output.push(c);
+        idx += length;
+    }
+    output
 }
 
 #[cfg(test)]
@@ -307,8 +409,80 @@ mod test {
 
     #[test]
     fn quote() -> Result<()> {
-        let ttypes = get_ttypes(Lexer::new("'", "").proc_tokens()?);
-        assert_eq!(ttypes, vec![TType::Quote]);
+        let ttypes = get_ttypes(Lexer::new("' ` ~ ~@", "").proc_tokens()?);
+        assert_eq!(ttypes, vec![TType::Quote, TType::Quasiquote, TType::Unquote, TType::UnquoteSplicing]);
+        Ok(())
+    }
+
+    #[test]
+    fn interpolation() -> Result<()> {
+        let tokens = Lexer::new("\"x = ${x}\n${(f \"}\")}\\${y}\"", "").proc_tokens()?;
+        assert_eq!(
+            get_ttypes(tokens.clone()),
+            vec![
+                TType::Interpolation(5),
+                TType::Str("x = ".to_string()),
+                TType::LBrace,
+                TType::Ident("x".to_string()),
+                TType::RBrace,
+                TType::Str("\n".to_string()),
+                TType::LBrace,
+                TType::LParen,
+                TType::Ident("f".to_string()),
+                TType::Str("}".to_string()),
+                TType::RParen,
+                TType::RBrace,
+                TType::Str("${y}".to_string()),
+            ]
+        );
+        let lines = Lexer::new("\"a\nb ${\n  x}\"", "").proc_tokens()?.into_iter().map(|t| t.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![1, 2, 2, 3, 3]);
+        assert!(Lexer::new("\"${x\"", "").proc_tokens().is_err());
+        // Non-ASCII text before and inside the interpolations.
+        let ttypes = get_ttypes(Lexer::new("(f \"é ${s} à\" \"ü${\"ß\"}\")", "").proc_tokens()?);
+        assert_eq!(ttypes, vec![
+            TType::LParen,
+            TType::Ident("f".to_string()),
+            TType::Interpolation(3),
+            TType::Str("é ".to_string()),
+            TType::LBrace,
+            TType::Ident("s".to_string()),
+            TType::RBrace,
+            TType::Str(" à".to_string()),
+            TType::Interpolation(2),
+            TType::Str("ü".to_string()),
+            TType::LBrace,
+            TType::Str("ß".to_string()),
+            TType::RBrace,
+            TType::RParen,
+        ]);
+        // An escaped backslash does not escape the interpolation following it.
+        let ttypes = get_ttypes(Lexer::new("\"\\\\${x}\\\\n\"", "").proc_tokens()?);
+        assert_eq!(ttypes, vec![
+            TType::Interpolation(3),
+            TType::Str("\\".to_string()),
+            TType::LBrace,
+            TType::Ident("x".to_string()),
+            TType::RBrace,
+            TType::Str("\\n".to_string()),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn dispatch() -> Result<()> {
+        let ttypes = get_ttypes(Lexer::new("#{a} #\"b+\" #t", "").proc_tokens()?);
+        assert_eq!(
+            ttypes,
+            vec![
+                TType::Dispatch('{'),
+                TType::Ident("a".to_string()),
+                TType::RBrace,
+                TType::Dispatch('"'),
+                TType::Str("b+".to_string()),
+                TType::Ident("#t".to_string()),
+            ]
+        );
         Ok(())
     }
 
@@ -326,6 +500,8 @@ mod test {
     fn string() -> Result<()> {
         let ttypes = get_ttypes(Lexer::new(r#""Hello, World !""#, "").proc_tokens()?);
         assert_eq!(ttypes, vec![TType::Str("Hello, World !".to_string())]);
+        let ttypes = get_ttypes(Lexer::new("\"été\" \"\\t\\x1b\"", "").proc_tokens()?);
+        assert_eq!(ttypes, vec![TType::Str("été".to_string()), TType::Str("\t\x1b".to_string())]);
         Ok(())
     }
 
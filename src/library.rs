@@ -0,0 +1,54 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// The directory under which the embedded standard library is exposed to the `Compiler`.
+pub const ROOT: &str = "<std>";
+
+// The standard library, embedded at build time so that the binary works without $ORION_LIB.
+const FILES: [(&str, &str); 9] = [
+    ("prelude.orn", include_str!("../lib/prelude.orn")),
+    ("bool.orn", include_str!("../lib/bool.orn")),
+    ("code.orn", include_str!("../lib/code.orn")),
+    ("function.orn", include_str!("../lib/function.orn")),
+    ("io.orn", include_str!("../lib/io.orn")),
+    ("list.orn", include_str!("../lib/list.orn")),
+    ("math.orn", include_str!("../lib/math.orn")),
+    ("maybe.orn", include_str!("../lib/maybe.orn")),
+    ("string.orn", include_str!("../lib/string.orn")),
+];
+
+// Returns the content of an embedded file, given as `<std>/name.orn`.
+pub fn read(path: &str) -> Option<&'static str> {
+    let name = path.strip_prefix(ROOT)?.strip_prefix(['/', '\\'])?;
+    FILES.iter().find(|(file, _)| *file == name).map(|(_, content)| *content)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn embedded() {
+        assert!(read("<std>/prelude.orn").unwrap().contains("(load \"list.orn\")"));
+        assert!(read("<std>/missing.orn").is_none());
+        assert!(read("lib/list.orn").is_none());
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{
+    error,
+    parser::{Expr, ExprT, MacroParam, Pattern},
+    Result,
+};
+use std::collections::HashMap;
+
+// Maximum amount of nested macro expansions, to stop macros that expand to themselves forever.
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+// Stack size of the VM running the procedural macros.
+pub const MACRO_STACK_SIZE: usize = 16000;
+
+#[derive(Clone, Debug)]
+enum Binding {
+    One(Expr),
+    Many(Vec<Expr>), // Bound by a `& rest` parameter.
+}
+
+// Reads an expression as a list of expressions, for nested macro parameters.
+fn as_list(expr: &Expr) -> Option<Vec<Expr>> {
+    match &expr.exprt {
+        ExprT::Call(func, args) => {
+            let mut list = vec![(**func).clone()];
+            list.extend(args.iter().cloned());
+            Some(list)
+        }
+        ExprT::Constr(name, args) if !args.is_empty() => {
+            let mut list = vec![Expr::new(ExprT::Constr(name.to_string(), vec![])).line(expr.line)];
+            list.extend(args.iter().cloned());
+            Some(list)
+        }
+        _ => None,
+    }
+}
+
+fn bind(param: &MacroParam, arg: &Expr, bindings: &mut HashMap<String, Binding>) -> bool {
+    match param {
+        MacroParam::Var(name) => {
+            bindings.insert(name.to_string(), Binding::One(arg.clone()));
+            true
+        }
+        MacroParam::List(params, rest) => match as_list(arg) {
+            Some(args) => bind_all(params, rest, &args, bindings),
+            None => false,
+        },
+    }
+}
+
+fn bind_all(params: &[MacroParam], rest: &Option<String>, args: &[Expr], bindings: &mut HashMap<String, Binding>) -> bool {
+    let fits = match rest {
+        Some(_) => args.len() >= params.len(),
+        None => args.len() == params.len(),
+    };
+    if !fits || !params.iter().zip(args).all(|(param, arg)| bind(param, arg, bindings)) {
+        return false;
+    }
+    if let Some(rest) = rest {
+        bindings.insert(rest.to_string(), Binding::Many(args[params.len()..].to_vec()));
+    }
+    true
+}
+
+pub struct Expander<'a> {
+    bindings: HashMap<String, Binding>,
+    renames: HashMap<String, String>,
+    gensym: &'a mut usize,
+    file: String,
+    line: usize,
+}
+
+impl<'a> Expander<'a> {
+    // Binds the arguments to the parameters of the first clause that fits them.
+    pub fn new(clauses: &[(MacroParam, Expr)], args: &[Expr], gensym: &'a mut usize, file: String, line: usize) -> Option<(Self, Expr)> {
+        let (bindings, body) = clauses.iter().find_map(|(params, body)| {
+            let mut bindings = HashMap::new();
+            match params {
+                MacroParam::List(params, rest) if bind_all(params, rest, args, &mut bindings) => Some((bindings, body.clone())),
+                _ => None,
+            }
+        })?;
+        Some((Self {
+            bindings,
+            renames: HashMap::new(),
+            gensym,
+            file,
+            line,
+        }, body))
+    }
+    // Returns the arguments bound to each parameter, and whether it is a `& rest` one.
+    pub fn arguments(&self) -> Vec<(String, Vec<Expr>, bool)> {
+        let mut arguments = self.bindings.iter().map(|(name, binding)| match binding {
+            Binding::One(expr) => (name.to_string(), vec![expr.clone()], false),
+            Binding::Many(exprs) => (name.to_string(), exprs.clone(), true),
+        }).collect::<Vec<_>>();
+        arguments.sort_by(|a, b| a.0.cmp(&b.0));
+        arguments
+    }
+    fn fresh(&mut self, name: &str) -> String {
+        *self.gensym += 1;
+        format!("{}#{}", name, self.gensym)
+    }
+    // Returns the names a binder of the macro body stands for.
+    fn binder(&mut self, name: &str) -> Result<Vec<String>> {
+        match self.bindings.get(name) {
+            Some(Binding::One(Expr { exprt: ExprT::Var(v), .. })) => Ok(vec![v.to_string()]),
+            Some(Binding::Many(exprs)) => exprs.iter().map(|e| match &e.exprt {
+                ExprT::Var(v) => Ok(v.to_string()),
+                _ => error!(self.file, e.line => "Expected an identifier as macro argument for {}.", name),
+            }).collect(),
+            Some(Binding::One(e)) => error!(self.file, e.line => "Expected an identifier as macro argument for {}.", name),
+            None => {
+                let renamed = self.fresh(name);
+                self.renames.insert(name.to_string(), renamed.clone());
+                Ok(vec![renamed])
+            }
+        }
+    }
+    fn reference(&self, name: &str) -> String {
+        match (self.bindings.get(name), self.renames.get(name)) {
+            (Some(Binding::One(Expr { exprt: ExprT::Var(v), .. })), _) => v.to_string(),
+            (_, Some(renamed)) => renamed.to_string(),
+            _ => name.to_string(),
+        }
+    }
+    fn pattern(&self, pat: &Pattern) -> Pattern {
+        match pat {
+            Pattern::Var(v) if v.as_str() == "_" => pat.clone(),
+            Pattern::Var(v) => Pattern::Var(self.reference(v)),
+            Pattern::Constr(name, pats) => Pattern::Constr(name.to_string(), pats.iter().map(|p| self.pattern(p)).collect()),
+            Pattern::Tuple(pats) => Pattern::Tuple(pats.iter().map(|p| self.pattern(p)).collect()),
+            Pattern::Or(pats) => Pattern::Or(pats.iter().map(|p| self.pattern(p)).collect()),
+            Pattern::As(name, pat) => Pattern::As(self.reference(name), Box::new(self.pattern(pat))),
+            Pattern::Prefix(prefix, rest) => Pattern::Prefix(prefix.to_string(), Box::new(self.pattern(rest))),
+            Pattern::Pin(name) => Pattern::Pin(self.reference(name)),
+            Pattern::Literal(_) | Pattern::Range(..) => pat.clone(),
+        }
+    }
+    fn expand_list(&mut self, exprs: &[Expr]) -> Result<Vec<Expr>> {
+        let mut to_ret = vec![];
+        for expr in exprs {
+            match &expr.exprt {
+                ExprT::Var(v) => match self.bindings.get(v) {
+                    Some(Binding::Many(exprs)) => to_ret.extend(exprs.iter().cloned()),
+                    _ => to_ret.push(self.expand(expr)?),
+                },
+                _ => to_ret.push(self.expand(expr)?),
+            }
+        }
+        Ok(to_ret)
+    }
+    // Substitutes the arguments in the macro body and renames the variables it binds, so that they cannot capture the caller's ones.
+    pub fn expand(&mut self, expr: &Expr) -> Result<Expr> {
+        let exprt = match &expr.exprt {
+            ExprT::Var(v) => match self.bindings.get(v) {
+                Some(Binding::One(arg)) => return Ok(arg.clone()),
+                Some(Binding::Many(_)) => return error!(self.file, self.line => "Variadic macro parameter used outside of an argument list: {}.", v),
+                None => ExprT::Var(self.reference(v)),
+            },
+            ExprT::Call(func, args) => {
+                let mut list = vec![(**func).clone()];
+                list.extend(args.iter().cloned());
+                let mut list = self.expand_list(&list)?;
+                if list.is_empty() {
+                    return error!(self.file, self.line => "Empty macro argument in function position.");
+                }
+                let func = list.remove(0);
+                ExprT::Call(Box::new(func), list)
+            }
+            ExprT::Lambda(args, body) => {
+                let saved = self.renames.clone();
+                let args = args.iter().map(|a| self.binder(a)).collect::<Result<Vec<Vec<String>>>>()?.concat();
+                let body = self.expand(body)?;
+                self.renames = saved;
+                ExprT::Lambda(args, Box::new(body))
+            }
+            ExprT::Def(name, value, impure) => {
+                // Renamed before its value, that may refer to it, and until the end of the enclosing scope.
+                let name = match self.binder(name)?.as_slice() {
+                    [name] => name.to_string(),
+                    _ => return error!(self.file, self.line => "Expected a single identifier as definition name for {}.", name),
+                };
+                ExprT::Def(name, Box::new(self.expand(value)?), *impure)
+            }
+            ExprT::Constr(name, args) => ExprT::Constr(name.to_string(), self.expand_list(args)?),
+            ExprT::Tuple(args) => ExprT::Tuple(self.expand_list(args)?),
+            ExprT::Begin(args) => ExprT::Begin(self.expand_list(args)?),
+            ExprT::Builtin(name, args) => ExprT::Builtin(name.to_string(), self.expand_list(args)?),
+            ExprT::Match(value, arms) => {
+                let value = self.expand(value)?;
+                let arms = arms.iter().map(|(pat, arm)| {
+                    let saved = self.renames.clone();
+                    for var in pat.variables() {
+                        self.binder(&var)?;
+                    }
+                    let pat = self.pattern(pat);
+                    let arm = self.expand(arm)?;
+                    self.renames = saved;
+                    Ok((pat, arm))
+                }).collect::<Result<Vec<_>>>()?;
+                ExprT::Match(Box::new(value), arms)
+            }
+            ExprT::Literal(_) | ExprT::Macro(..) | ExprT::ProcMacro(..) | ExprT::Reader(..) | ExprT::Enum(..) | ExprT::Load(_) | ExprT::Module(..) | ExprT::Import(..) => expr.exprt.clone(),
+        };
+        Ok(Expr::new(exprt).line(self.line))
+    }
+}
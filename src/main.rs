@@ -20,9 +20,19 @@
  */
 mod bytecode;
 mod compiler;
+mod decision;
 mod errors;
+mod exhaustiveness;
+mod gc;
 mod lexer;
+mod library;
+mod macros;
+mod modules;
+mod optimizer;
 mod parser;
+mod quote;
+mod resolver;
+mod shaker;
 mod vm;
 mod cli;
 
@@ -73,6 +83,20 @@ fn print_err(e: OrionError) {
         },
         e.2);
 }
+fn print_warning(w: OrionError) {
+    eprintln!(
+        "{}{}{}",
+        match (w.0, w.1) {
+            (Some(file), Some(line)) => format!("{}:{}: ", file, line),
+            _ => "".to_string(),
+        },
+        if cfg!(windows) {
+            "Warning: "
+        } else {
+            "\x1b[0;33mWarning: \x1b[0m"
+        },
+        w.2);
+}
 fn main() {
     match cli() {
         Ok(()) => {}
@@ -0,0 +1,77 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// A module compiled once, whatever the amount of files importing it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub file: String,
+    pub exports: Vec<String>,
+}
+
+impl Module {
+    pub fn qualify(&self, name: &str) -> String {
+        format!("{}.{}", self.name, name)
+    }
+}
+
+// The names visible in a file besides the local variables. Files that are not modules share the same namespace.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Namespace {
+    pub module: Option<Module>, // The module being compiled, if any.
+    pub definitions: Vec<String>, // Unqualified names defined by the module being compiled.
+    aliases: Vec<(String, String)>, // (alias, module)
+    names: Vec<(String, String)>, // (name, qualified name)
+    prelude: Vec<(String, String)>, // The names bound by the prelude, that modules start with.
+}
+
+impl Namespace {
+    // The namespace of a module, seeing the names of the prelude like the other files.
+    pub fn new(module: Module, outer: &Namespace) -> Self {
+        Self {
+            module: Some(module),
+            names: outer.prelude.clone(),
+            prelude: outer.prelude.clone(),
+            ..Self::default()
+        }
+    }
+    // Records the names bound so far as the ones of the prelude.
+    pub fn end_prelude(&mut self) {
+        self.prelude = self.names.clone();
+    }
+    // Binds an unqualified name, shadowing the previous binding of this name.
+    pub fn bind(&mut self, name: impl ToString, qualified: impl ToString) {
+        self.names.push((name.to_string(), qualified.to_string()));
+    }
+    pub fn is_bound(&self, name: &str) -> bool {
+        self.names.iter().any(|(n, _)| n == name)
+    }
+    pub fn lookup(&self, name: &str) -> Option<&str> {
+        self.names.iter().rev().find(|(n, _)| n == name).map(|(_, q)| q.as_str())
+    }
+    pub fn alias(&mut self, alias: impl ToString, module: impl ToString) {
+        self.aliases.push((alias.to_string(), module.to_string()));
+    }
+    // Returns the module an alias, or a module name, stands for.
+    pub fn module_of(&self, alias: &str) -> Option<&str> {
+        self.aliases.iter().rev().find(|(a, _)| a == alias).map(|(_, m)| m.as_str())
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{exhaustiveness::Pat, parser::Literal};
+
+// A value known at compile time, made of literals, constructors and tuples.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Known {
+    Literal(Literal),
+    Constr(u16, Vec<Known>), // (constr_id, [value])
+    Tuple(Vec<Known>),
+}
+
+// Runs a pure builtin on constant arguments as the VM would, unless it would fail or overflow, so that it still does at run time.
+pub fn fold(builtin: &str, args: &[Literal]) -> Option<Literal> {
+    use Literal::{Integer, Single};
+    Some(match (builtin, args) {
+        ("add", [Integer(lhs), Integer(rhs)]) => Integer(lhs.checked_add(*rhs)?),
+        ("add", [Single(lhs), Single(rhs)]) => Single(lhs + rhs),
+        ("sub", [Integer(lhs), Integer(rhs)]) => Integer(lhs.checked_sub(*rhs)?),
+        ("sub", [Single(lhs), Single(rhs)]) => Single(lhs - rhs),
+        ("mul", [Integer(lhs), Integer(rhs)]) => Integer(lhs.checked_mul(*rhs)?),
+        ("mul", [Single(lhs), Single(rhs)]) => Single(lhs * rhs),
+        ("div", [Integer(lhs), Integer(rhs)]) => Integer(lhs.checked_div(*rhs)?),
+        ("div", [Single(lhs), Single(rhs)]) => Single(lhs / rhs),
+        ("neg", [Integer(val)]) => Integer(val.checked_neg()?),
+        ("neg", [Single(val)]) => Single(-val),
+        ("cos", [Single(val)]) => Single(val.cos()),
+        ("sin", [Single(val)]) => Single(val.sin()),
+        ("tan", [Single(val)]) => Single(val.tan()),
+        ("acos", [Single(val)]) => Single(val.acos()),
+        ("asin", [Single(val)]) => Single(val.asin()),
+        ("atan", [Single(val)]) => Single(val.atan()),
+        ("_cmp", [lhs, rhs]) => {
+            let ordering = match (lhs, rhs) {
+                (Integer(lhs), Integer(rhs)) => lhs.cmp(rhs),
+                (Single(lhs), Single(rhs)) => lhs.partial_cmp(rhs)?,
+                (Literal::String(lhs), Literal::String(rhs)) => lhs.cmp(rhs),
+                _ => return None,
+            };
+            // The index of the ordering in `Less`, `Equal` and `Greater`.
+            Integer(ordering as i32 + 1)
+        }
+        _ => return None,
+    })
+}
+
+// Whether all the patterns match their value, `None` if it cannot be told before running.
+fn all(pats: &[Pat], values: &[Known]) -> Option<bool> {
+    let outcomes = pats.iter().zip(values).map(|(pat, value)| matches(pat, value)).collect::<Vec<_>>();
+    if outcomes.contains(&Some(false)) {
+        Some(false)
+    } else if outcomes.iter().all(Option::is_some) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+// Whether a pattern matches a known value, `None` if it cannot be told before running.
+pub fn matches(pat: &Pat, value: &Known) -> Option<bool> {
+    match (pat, value) {
+        (Pat::Any, _) => Some(true),
+        (Pat::Opaque(_) | Pat::Prefix(_), _) => None,
+        (Pat::Or(alternatives), _) => {
+            let outcomes = alternatives.iter().map(|alt| matches(alt, value)).collect::<Vec<_>>();
+            if outcomes.contains(&Some(true)) {
+                Some(true)
+            } else if outcomes.iter().all(|o| o == &Some(false)) {
+                Some(false)
+            } else {
+                None
+            }
+        }
+        (Pat::Literal(lit), Known::Literal(val)) => Some(lit == val),
+        (Pat::Range(Literal::Integer(start), Literal::Integer(end)), Known::Literal(Literal::Integer(i))) => Some((start..=end).contains(&i)),
+        (Pat::Range(Literal::Single(start), Literal::Single(end)), Known::Literal(Literal::Single(s))) => Some((start..=end).contains(&s)),
+        (Pat::Constr(idx, pats), Known::Constr(id, values)) if idx == id => all(pats, values),
+        (Pat::Tuple(pats), Known::Tuple(values)) if pats.len() == values.len() => all(pats, values),
+        _ => Some(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn folding() {
+        assert_eq!(fold("add", &[Literal::Integer(1), Literal::Integer(2)]), Some(Literal::Integer(3)));
+        assert_eq!(fold("_cmp", &[Literal::String("a".to_string()), Literal::String("b".to_string())]), Some(Literal::Integer(0)));
+        // Failures are left to the VM.
+        assert_eq!(fold("div", &[Literal::Integer(1), Literal::Integer(0)]), None);
+        assert_eq!(fold("add", &[Literal::Integer(i32::MAX), Literal::Integer(1)]), None);
+        assert_eq!(fold("add", &[Literal::Integer(1), Literal::Single(1.)]), None);
+    }
+
+    #[test]
+    fn static_matches() {
+        let just = |v: i32| Known::Constr(1, vec![Known::Literal(Literal::Integer(v))]);
+        assert_eq!(matches(&Pat::Constr(1, vec![Pat::Any]), &just(3)), Some(true));
+        assert_eq!(matches(&Pat::Constr(0, vec![]), &just(3)), Some(false));
+        assert_eq!(matches(&Pat::Constr(1, vec![Pat::Literal(Literal::Integer(4))]), &just(3)), Some(false));
+        assert_eq!(matches(&Pat::Constr(1, vec![Pat::Opaque("^x".to_string())]), &just(3)), None);
+        assert_eq!(matches(&Pat::Or(vec![Pat::Literal(Literal::Integer(2)), Pat::Range(Literal::Integer(0), Literal::Integer(5))]), &Known::Literal(Literal::Integer(3))), Some(true));
+    }
+}
@@ -23,6 +23,7 @@ use crate::{
     lexer::{TType, Token},
     Result,
 };
+use crate::quote::{form, list, quote, symbol};
 use std::{collections::HashMap, mem::discriminant};
 
 #[derive(PartialEq, Debug, Clone)]
@@ -43,20 +44,6 @@ impl Expr {
             line,
         }
     }
-    pub fn replace(&self, from: String, to: Self) -> Self {
-        match self.exprt.clone() {
-            ExprT::Var(v) if v == from => to,
-            ExprT::Call(f, args) => Expr::new(ExprT::Call(Box::new(f.replace(from.clone(), to.clone())), args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Lambda(args, content) => Expr::new(ExprT::Lambda(args, Box::new(content.replace(from, to)))),
-            ExprT::Def(name, val, impure) => Expr::new(ExprT::Def(name, Box::new(val.replace(from, to)), impure)),
-            ExprT::Constr(name, args) => Expr::new(ExprT::Constr(name, args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Tuple(args) => Expr::new(ExprT::Tuple(args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Match(val, pats) => Expr::new(ExprT::Match(Box::new(val.replace(from.clone(), to.clone())), pats.into_iter().map(|(p, e)| (p, e.replace(from.clone(), to.clone()))).collect())),
-            ExprT::Begin(args) => Expr::new(ExprT::Begin(args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Builtin(name, args) => Expr::new(ExprT::Builtin(name, args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            _ => self.clone(),
-        }.line(self.line)
-    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -66,16 +53,26 @@ pub enum ExprT {
     Lambda(Vec<String>, Box<Expr>),
     Literal(Literal),
     Def(String, Box<Expr>, bool), // (name, value, impure?)
-    Macro(String, Vec<String>, Box<Expr>),
+    Macro(String, Vec<(MacroParam, Expr)>), // (name, [(params, body)])
+    ProcMacro(String, MacroParam, Box<Expr>), // (name, params, body)
+    Reader(String, String), // (dispatch, macro)
     Constr(String, Vec<Expr>),
     Enum(String, HashMap<String, u8>),
     Tuple(Vec<Expr>),
     Load(Vec<String>),
+    Module(String, Vec<String>), // (name, exports)
+    Import(String, Option<String>, Option<Vec<String>>), // (module, alias, only)
     Match(Box<Expr>, Vec<(Pattern, Expr)>),
     Begin(Vec<Expr>),
     Builtin(String, Vec<Expr>),
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroParam {
+    Var(String),
+    List(Vec<MacroParam>, Option<String>), // ([param], rest)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Integer(i32),
@@ -89,11 +86,59 @@ pub enum Pattern {
     Constr(String, Vec<Pattern>),
     Tuple(Vec<Pattern>),
     Literal(Literal),
+    Or(Vec<Pattern>),
+    As(String, Box<Pattern>),
+    Range(Literal, Literal),
+    Prefix(String, Box<Pattern>),
+    Pin(String),
 }
+impl Pattern {
+    pub fn variables(&self) -> Vec<String> {
+        let mut vars = match self {
+            Pattern::Var(v) if v.as_str() == "_" => vec![],
+            Pattern::Var(v) => vec![v.to_string()],
+            Pattern::Constr(_, pats) | Pattern::Tuple(pats) => pats.iter().flat_map(|p| p.variables()).collect(),
+            Pattern::Literal(_) | Pattern::Range(..) | Pattern::Pin(_) => vec![],
+            Pattern::Prefix(_, rest) => rest.variables(),
+            Pattern::Or(alternatives) => alternatives.first().map_or(vec![], |p| p.variables()),
+            Pattern::As(name, pat) => {
+                let mut vars = pat.variables();
+                vars.push(name.to_string());
+                vars
+            }
+        };
+        vars.sort();
+        vars
+    }
+    // The variables compared to by the `^` patterns.
+    pub fn pins(&self) -> Vec<String> {
+        match self {
+            Pattern::Pin(name) => vec![name.to_string()],
+            Pattern::Constr(_, pats) | Pattern::Tuple(pats) | Pattern::Or(pats) => pats.iter().flat_map(|p| p.pins()).collect(),
+            Pattern::Prefix(_, pat) | Pattern::As(_, pat) => pat.pins(),
+            Pattern::Var(_) | Pattern::Literal(_) | Pattern::Range(..) => vec![],
+        }
+    }
+}
+// Returns the token closing a reader macro started by `#` and `delimiter`.
+fn closing(delimiter: char) -> TType {
+    match delimiter {
+        '(' => TType::RParen,
+        '[' => TType::RBracket,
+        '{' => TType::RBrace,
+        _ => bug!("UNEXPECTED_DISPATCH_CHARACTER"),
+    }
+}
+
 fn first_char(s: impl ToString) -> char {
     s.to_string().chars().nth(0).unwrap()
 }
 
+// Whether an identifier names an enum variant, possibly qualified by a module, as in `shapes.Rect`.
+fn is_constructor(ident: &str) -> bool {
+    first_char(ident).is_ascii_uppercase() || matches!(ident.rsplit('.').next().and_then(|name| name.chars().next()), Some(c) if c.is_ascii_uppercase())
+}
+
 pub struct Parser {
     input: Vec<Token>,
     output: Vec<Expr>,
@@ -161,6 +206,16 @@ impl Parser {
         Ok(toret)
     }
 
+    fn parse_pattern_literal(&mut self) -> Result<Literal> {
+        let root = self.pop()?;
+
+        match root.ttype {
+            TType::Str(s) => Ok(Literal::String(s)),
+            TType::Number(i) => Ok(Literal::Integer(i)),
+            TType::Float(f) => Ok(Literal::Single(f)),
+            _ => error!(self.file, root.line => "Expected Literal, found {}.", root.ttype.get_type()),
+        }
+    }
     fn parse_pattern(&mut self) -> Result<Pattern> {
         let root = self.pop()?;
 
@@ -169,17 +224,82 @@ impl Parser {
             TType::Number(i) => Pattern::Literal(Literal::Integer(*i)),
             TType::Float(f) => Pattern::Literal(Literal::Single(*f)),
             TType::Ident(v) => {
-                if first_char(&v).is_ascii_uppercase() {
+                if is_constructor(v) {
                     Pattern::Constr(v.to_string(), vec![])
                 } else {
                     Pattern::Var(v.to_string())
                 }
             }
+            TType::LBracket => {
+                let mut pats = vec![];
+                let mut tail = Pattern::Constr("Nil".to_string(), vec![]);
+                while !self.is_at_end() && self.peek().unwrap().ttype != TType::RBracket {
+                    if self.peek().unwrap().ttype == TType::Ident("&".to_string()) {
+                        self.advance(TType::Ident("".to_string()))?;
+                        tail = self.parse_pattern()?;
+                        break;
+                    }
+                    pats.push(self.parse_pattern()?);
+                }
+                self.advance(TType::RBracket)?;
+                pats.into_iter().rev().fold(tail, |acc, p| Pattern::Constr("Cons".to_string(), vec![p, acc]))
+            }
             TType::LParen => {
                 let subroot = self.pop()?;
 
                 match &subroot.ttype {
                     TType::RParen => Pattern::Tuple(vec![]),
+                    TType::Ident(x) if x.as_str() == "|" => {
+                        let mut alternatives = vec![];
+                        while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                            alternatives.push(self.parse_pattern()?);
+                        }
+                        self.advance(TType::RParen)?;
+
+                        if alternatives.is_empty() {
+                            return error!(self.file, subroot.line => "Expected at least one alternative in or-pattern.");
+                        }
+                        Pattern::Or(alternatives)
+                    }
+                    TType::Ident(x) if x.as_str() == ".." => {
+                        let start = self.parse_pattern_literal()?;
+                        let end = self.parse_pattern_literal()?;
+                        self.advance(TType::RParen)?;
+                        Pattern::Range(start, end)
+                    }
+                    TType::Ident(x) if x.as_str() == "++" => {
+                        let prefix = match self.parse_pattern_literal()? {
+                            Literal::String(s) => s,
+                            _ => return error!(self.file, subroot.line => "Expected a String prefix."),
+                        };
+                        let rest = self.parse_pattern()?;
+                        self.advance(TType::RParen)?;
+                        Pattern::Prefix(prefix, Box::new(rest))
+                    }
+                    TType::Ident(x) if x.as_str() == "^" => {
+                        let r_name = self.advance(TType::Ident("".to_string()))?;
+                        let name = if let TType::Ident(n) = r_name.ttype {
+                            n
+                        } else {
+                            bug!("UNEXPECTED_NON_IDENTIFIER");
+                        };
+                        self.advance(TType::RParen)?;
+                        Pattern::Pin(name)
+                    }
+                    TType::Ident(x) if x.as_str() == "@" => {
+                        let r_name = self.advance(TType::Ident("".to_string()))?;
+                        let name = if let TType::Ident(n) = r_name.ttype {
+                            n
+                        } else {
+                            bug!("UNEXPECTED_NON_IDENTIFIER");
+                        };
+                        if first_char(&name).is_ascii_uppercase() {
+                            return error!(self.file, r_name.line => "Variable names have to start with a lowercase letter: {}.", name);
+                        }
+                        let pat = self.parse_pattern()?;
+                        self.advance(TType::RParen)?;
+                        Pattern::As(name, Box::new(pat))
+                    }
                     TType::Tuple => {
                         let mut args = vec![];
                         while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
@@ -198,7 +318,7 @@ impl Parser {
                         self.advance(TType::RParen)?;
 
                         if let TType::Ident(x) = &subroot.ttype {
-                            if first_char(x).is_ascii_uppercase() {
+                            if is_constructor(x) {
                                 Pattern::Constr(x.to_string(), args)
                             } else {
                                 return error!(
@@ -230,13 +350,139 @@ impl Parser {
                 return error!(
                     self.file,
                     root.line =>
-                    "Expected Literal, Identifier, Tuple or Enum Variant, found {}.",
+                    "Expected Literal, Identifier, Tuple, List or Enum Variant, found {}.",
                     root.ttype.get_type()
                     )
             }
         })
     }
 
+    fn parse_macro_params(&mut self) -> Result<MacroParam> {
+        let root = self.pop()?;
+
+        match root.ttype {
+            TType::Ident(name) if name.as_str() != "&" => Ok(MacroParam::Var(name)),
+            TType::LParen => {
+                let mut params = vec![];
+                let mut rest = None;
+                while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                    if self.peek().unwrap().ttype == TType::Ident("&".to_string()) {
+                        self.advance(TType::Ident("".to_string()))?;
+                        match self.advance(TType::Ident("".to_string()))?.ttype {
+                            TType::Ident(s) => rest = Some(s),
+                            _ => bug!("UNEXPECTED_NON_IDENT"),
+                        }
+                        break;
+                    }
+                    params.push(self.parse_macro_params()?);
+                }
+                self.advance(TType::RParen)?;
+                Ok(MacroParam::List(params, rest))
+            }
+            _ => error!(self.file, root.line => "Expected a macro parameter, found {}.", root.ttype.get_type()),
+        }
+    }
+
+    fn module_name(&mut self) -> Result<String> {
+        let token = self.advance(TType::Ident("".to_string()))?;
+        match token.ttype {
+            TType::Ident(name) if !name.contains('.') => Ok(name),
+            TType::Ident(name) => error!(self.file, token.line => "Module names cannot contain dots: {}.", name),
+            _ => bug!("UNEXPECTED_NON_IDENT"),
+        }
+    }
+    // Parses the rest of a list of names, as in `(export ...)` and `(only ...)`.
+    fn names(&mut self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+            let token = self.pop()?;
+            match token.ttype {
+                TType::Ident(name) | TType::Builtin(name) => names.push(name),
+                x => return error!(self.file, token.line => "Expected an Identifier, found a {}.", x.get_type()),
+            }
+        }
+        self.advance(TType::RParen)?;
+        Ok(names)
+    }
+    // Parses the expression building the quoted code of a datum, unquoted expressions are evaluated if `quasi` is set.
+    fn parse_datum(&mut self, quasi: bool) -> Result<Expr> {
+        let root = self.pop()?;
+        let line = root.line;
+
+        let items = |parser: &mut Self, end: TType| -> Result<Expr> {
+            let mut items = vec![];
+            while !parser.is_at_end() && parser.peek().unwrap().ttype != end {
+                if quasi && parser.peek().unwrap().ttype == TType::UnquoteSplicing {
+                    parser.advance(TType::UnquoteSplicing)?;
+                    items.push((true, parser.parse_expr()?));
+                } else {
+                    items.push((false, parser.parse_datum(quasi)?));
+                }
+            }
+            parser.advance(end)?;
+            Ok(items.into_iter().rev().fold(list(vec![], line), |acc, (spliced, item)| if spliced {
+                Expr::new(ExprT::Builtin("_append".to_string(), vec![item, acc])).line(line)
+            } else {
+                Expr::new(ExprT::Constr("Cons".to_string(), vec![item, acc])).line(line)
+            }))
+        };
+
+        Ok(match root.ttype {
+            TType::Str(s) => Expr::new(ExprT::Literal(Literal::String(s))).line(line),
+            TType::Number(i) => Expr::new(ExprT::Literal(Literal::Integer(i))).line(line),
+            TType::Float(f) => Expr::new(ExprT::Literal(Literal::Single(f))).line(line),
+            TType::Ident(v) => symbol(v, line),
+            TType::Builtin(b) => symbol(b, line),
+            TType::Def => symbol("def", line),
+            TType::Macro => symbol("macro", line),
+            TType::DefMacro => symbol("defmacro", line),
+            TType::Enum => symbol("enum", line),
+            TType::Tuple => symbol(",", line),
+            TType::Lambda => symbol("λ", line),
+            TType::Match => symbol("match", line),
+            TType::Load => symbol("load", line),
+            TType::Module => symbol("module", line),
+            TType::Import => symbol("import", line),
+            TType::Begin => symbol("begin", line),
+            TType::Quote => form("quote", vec![self.parse_datum(quasi)?], line),
+            TType::Quasiquote => form("quasiquote", vec![self.parse_datum(quasi)?], line),
+            TType::Unquote if quasi => self.parse_expr()?,
+            TType::Unquote => form("unquote", vec![self.parse_datum(quasi)?], line),
+            TType::UnquoteSplicing => return error!(self.file, line => "Unquote-splicing used outside of a list."),
+            TType::Interpolation(_) => {
+                self.current -= 1;
+                quote(&self.parse_expr()?)
+            }
+            TType::Dispatch('"') => match self.advance(TType::Str("".to_string()))?.ttype {
+                TType::Str(s) => list(vec![symbol("#\"", line), Expr::new(ExprT::Literal(Literal::String(s))).line(line)], line),
+                _ => bug!("UNEXPECTED_NON_STRING"),
+            },
+            TType::Dispatch(c) => {
+                let end = closing(c);
+                let mut items = vec![symbol(format!("#{}", c), line)];
+                while !self.is_at_end() && self.peek().unwrap().ttype != end {
+                    items.push(self.parse_datum(quasi)?);
+                }
+                self.advance(end)?;
+                list(items, line)
+            }
+            TType::LParen => items(self, TType::RParen)?,
+            TType::LBrace => {
+                let body = items(self, TType::RBrace)?;
+                Expr::new(ExprT::Constr("Cons".to_string(), vec![symbol("begin", line), body])).line(line)
+            }
+            TType::LBracket => {
+                let mut elements = vec![];
+                while !self.is_at_end() && self.peek().unwrap().ttype != TType::RBracket {
+                    elements.push(self.parse_datum(quasi)?);
+                }
+                self.advance(TType::RBracket)?;
+                elements.into_iter().rev().fold(symbol("Nil", line), |acc, e| form("Cons", vec![e, acc], line))
+            }
+            TType::RParen | TType::RBrace | TType::RBracket => return error!(self.file, line => "Unexpected {}.", root.ttype.get_type()),
+        })
+    }
+
     fn parse_expr(&mut self) -> Result<Expr> {
         let root = self.pop()?;
 
@@ -245,13 +491,42 @@ impl Parser {
             TType::Float(f) => Expr::new(ExprT::Literal(Literal::Single(*f))).line(root.line),
             TType::Number(i) => Expr::new(ExprT::Literal(Literal::Integer(*i))).line(root.line),
             TType::Ident(v) => {
-                if first_char(&v).is_ascii_uppercase() {
+                if is_constructor(v) {
                     Expr::new(ExprT::Constr(v.to_string(), vec![])).line(root.line)
                 } else {
                     Expr::new(ExprT::Var(v.to_string())).line(root.line)
                 }
             }
-            TType::Quote => Expr::new(ExprT::Lambda(vec![], Box::new(self.parse_expr()?))).line(root.line),
+            TType::Quote => self.parse_datum(false)?,
+            TType::Quasiquote => self.parse_datum(true)?,
+            TType::Interpolation(segments) => {
+                let parts = (0..*segments).map(|_| {
+                    let part = self.parse_expr()?;
+                    match part.exprt {
+                        ExprT::Begin(mut exprs) if exprs.len() == 1 => Ok(exprs.remove(0)),
+                        ExprT::Begin(_) => error!(self.file, part.line => "Expected exactly one expression in string interpolation."),
+                        _ => Ok(part),
+                    }
+                }).collect::<Result<Vec<Expr>>>()?;
+                // Every segment is displayed by its own placeholder, so that the amounts always match.
+                let formatter = Expr::new(ExprT::Literal(Literal::String("{}".repeat(parts.len())))).line(root.line);
+                Expr::new(ExprT::Builtin("format".to_string(), vec![formatter, Expr::new(ExprT::Tuple(parts)).line(root.line)])).line(root.line)
+            }
+            TType::Dispatch(c) => {
+                let items = if *c == '"' {
+                    vec![self.parse_expr()?]
+                } else {
+                    let end = closing(*c);
+                    let mut items = vec![];
+                    while !self.is_at_end() && self.peek().unwrap().ttype != end {
+                        items.push(self.parse_expr()?);
+                    }
+                    self.advance(end)?;
+                    items
+                };
+                // Expanded by the macro bound to the dispatch character.
+                Expr::new(ExprT::Call(Box::new(Expr::new(ExprT::Var(format!("#{}", c))).line(root.line)), items)).line(root.line)
+            }
             TType::LBrace => {
                 let mut expressions = vec![];
 
@@ -286,18 +561,68 @@ impl Parser {
                             bug!("UNEXPECTED_NON_IDENT")
                         };
 
+                        // `(macro name ((params) body) ...)` if the first parameter list starts with a list, `(macro name (params) body)` otherwise.
+                        let clauses = if self.input.get(self.current + 1).map(|t| &t.ttype) == Some(&TType::LParen) {
+                            let mut clauses = vec![];
+                            while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                                self.advance(TType::LParen)?;
+                                let params = self.parse_macro_params()?;
+                                let body = self.parse_expr()?;
+                                self.advance(TType::RParen)?;
+                                clauses.push((params, body));
+                            }
+                            clauses
+                        } else {
+                            self.advance(TType::LParen)?;
+                            self.current -= 1; // Let `parse_macro_params` parse the list.
+                            let params = self.parse_macro_params()?;
+                            let body = self.parse_expr()?;
+                            vec![(params, body)]
+                        };
+                        self.advance(TType::RParen)?;
+                        Expr::new(ExprT::Macro(name, clauses)).line(subroot.line)
+                    }
+                    TType::Ident(x) if x.as_str() == "quote" || x.as_str() == "quasiquote" => {
+                        let datum = self.parse_datum(x.as_str() == "quasiquote")?;
+                        self.advance(TType::RParen)?;
+                        datum
+                    }
+                    // `reader` is only a form when it binds a dispatch character, and stays usable as a name.
+                    TType::Ident(x) if x.as_str() == "reader" && matches!(self.peek().map(|t| t.ttype), Some(TType::Dispatch(_))) => {
+                        let dispatch = match self.pop()?.ttype {
+                            TType::Dispatch('"') => {
+                                self.advance(TType::Str("".to_string()))?;
+                                '"'
+                            }
+                            TType::Dispatch(c) => {
+                                self.advance(closing(c))?;
+                                c
+                            }
+                            x => return error!(self.file, subroot.line => "Expected a dispatch character, found a {}.", x.get_type()),
+                        };
+                        let name = match self.advance(TType::Ident("".to_string()))?.ttype {
+                            TType::Ident(s) => s,
+                            _ => bug!("UNEXPECTED_NON_IDENT"),
+                        };
+                        self.advance(TType::RParen)?;
+                        Expr::new(ExprT::Reader(format!("#{}", dispatch), name)).line(subroot.line)
+                    }
+                    TType::DefMacro => {
+                        let name = match self.advance(TType::Ident("".to_string()))?.ttype {
+                            TType::Ident(s) => s,
+                            _ => bug!("UNEXPECTED_NON_IDENT"),
+                        };
                         self.advance(TType::LParen)?;
-                        let mut args = vec![];
-                        while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
-                            match self.advance(TType::Ident("".to_string()))?.ttype {
-                                TType::Ident(s) => args.push(s),
-                                _ => bug!("UNEXPECTED_NON_IDENT")
+                        self.current -= 1; // Let `parse_macro_params` parse the list.
+                        let params = self.parse_macro_params()?;
+                        if let MacroParam::List(params, _) = &params {
+                            if params.iter().any(|p| matches!(p, MacroParam::List(..))) {
+                                return error!(self.file, subroot.line => "Procedural macro parameters have to be identifiers.");
                             }
                         }
+                        let body = self.parse_expr()?;
                         self.advance(TType::RParen)?;
-                        let expr = self.parse_expr()?;
-                        self.advance(TType::RParen)?;
-                        Expr::new(ExprT::Macro(name, args, Box::new(expr))).line(subroot.line)
+                        Expr::new(ExprT::ProcMacro(name, params, Box::new(body))).line(subroot.line)
                     }
                     TType::Builtin(b) => {
                         let mut args = vec![];
@@ -321,6 +646,34 @@ impl Parser {
                         self.advance(TType::RParen)?;
                         Expr::new(ExprT::Load(names)).line(subroot.line)                    
                     }
+                    TType::Module => {
+                        let name = self.module_name()?;
+                        self.advance(TType::LParen)?;
+                        if self.advance(TType::Ident("".to_string()))?.ttype != TType::Ident("export".to_string()) {
+                            return error!(self.file, subroot.line => "Expected an `export` list after the module name.");
+                        }
+                        let exports = self.names()?;
+                        self.advance(TType::RParen)?;
+                        Expr::new(ExprT::Module(name, exports)).line(subroot.line)
+                    }
+                    TType::Import => {
+                        let name = self.module_name()?;
+                        let (mut alias, mut only) = (None, None);
+                        while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                            match self.pop()?.ttype {
+                                TType::Ident(k) if k.as_str() == ":as" && alias.is_none() => alias = Some(self.module_name()?),
+                                TType::LParen if only.is_none() => {
+                                    if self.advance(TType::Ident("".to_string()))?.ttype != TType::Ident("only".to_string()) {
+                                        return error!(self.file, subroot.line => "Expected an `only` list.");
+                                    }
+                                    only = Some(self.names()?);
+                                }
+                                x => return error!(self.file, subroot.line => "Unexpected {} in import, expected `:as` or an `only` list.", x.get_type()),
+                            }
+                        }
+                        self.advance(TType::RParen)?;
+                        Expr::new(ExprT::Import(name, alias, only)).line(subroot.line)
+                    }
                     TType::Def => {
                         let impure =
                             if self.peek().and_then(|t| Some(t.ttype)) == Some(TType::Quote) {
@@ -475,7 +828,7 @@ impl Parser {
                         self.advance(TType::RParen)?;
 
                         if let TType::Ident(x) = &subroot.ttype {
-                            if first_char(x).is_ascii_uppercase() {
+                            if is_constructor(x) {
                                 Expr::new(ExprT::Constr(x.to_string(), args)).line(subroot.line)
                             } else {
                                 Expr::new(ExprT::Call(Box::new(func), args)).line(subroot.line)
@@ -673,6 +1026,49 @@ mod test {
         Ok(())
     }
     #[test]
+    fn patterns() -> Result<()> {
+        let tokens = Lexer::new("(match x ((| 1 2) 0) ((@ l [a & b]) 1))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        assert_eq!(
+            ast,
+            vec![Expr::new(ExprT::Match(
+                    Box::new(Expr::new(ExprT::Var("x".to_string()))),
+                    vec![
+                    (Pattern::Or(vec![Pattern::Literal(Literal::Integer(1)), Pattern::Literal(Literal::Integer(2))]),
+                    Expr::new(ExprT::Literal(Literal::Integer(0)))),
+                    (Pattern::As("l".to_string(), Box::new(Pattern::Constr("Cons".to_string(), vec![Pattern::Var("a".to_string()), Pattern::Var("b".to_string())]))),
+                    Expr::new(ExprT::Literal(Literal::Integer(1))))]))]);
+        Ok(())
+    }
+    #[test]
+    fn special_patterns() -> Result<()> {
+        let tokens = Lexer::new("(match x ((.. 0 9) 0) ((++ \"a\" r) 1) ((^ y) 2))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        assert_eq!(
+            ast,
+            vec![Expr::new(ExprT::Match(
+                    Box::new(Expr::new(ExprT::Var("x".to_string()))),
+                    vec![
+                    (Pattern::Range(Literal::Integer(0), Literal::Integer(9)), Expr::new(ExprT::Literal(Literal::Integer(0)))),
+                    (Pattern::Prefix("a".to_string(), Box::new(Pattern::Var("r".to_string()))), Expr::new(ExprT::Literal(Literal::Integer(1)))),
+                    (Pattern::Pin("y".to_string()), Expr::new(ExprT::Literal(Literal::Integer(2))))]))]);
+        Ok(())
+    }
+    #[test]
+    fn macros() -> Result<()> {
+        let tokens = Lexer::new("(macro id (x) x) (macro f (() 0) (((a b) & r) a))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let var = |v: &str| Expr::new(ExprT::Var(v.to_string()));
+        assert_eq!(
+            ast,
+            vec![
+            Expr::new(ExprT::Macro("id".to_string(), vec![(MacroParam::List(vec![MacroParam::Var("x".to_string())], None), var("x"))])),
+            Expr::new(ExprT::Macro("f".to_string(), vec![
+                                   (MacroParam::List(vec![], None), Expr::new(ExprT::Literal(Literal::Integer(0)))),
+                                   (MacroParam::List(vec![MacroParam::List(vec![MacroParam::Var("a".to_string()), MacroParam::Var("b".to_string())], None)], Some("r".to_string())), var("a"))]))]);
+        Ok(())
+    }
+    #[test]
     fn brackets() -> Result<()> {
         let tokens = Lexer::new("[1 2]", 0).proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
@@ -694,9 +1090,59 @@ mod test {
 
     #[test]
     fn quote() -> Result<()> {
-        let tokens = Lexer::new("'a", 0).proc_tokens()?;
+        let tokens = Lexer::new("'a '(f 5) `(f ~x ~@xs)", 0).proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
-        assert_eq!(ast, vec![Expr::new(ExprT::Lambda(vec![], Box::new(Expr::new(ExprT::Var("a".to_string())))))]);
+        let var = |v: &str| Expr::new(ExprT::Var(v.to_string()));
+        let cons = |x, xs| Expr::new(ExprT::Constr("Cons".to_string(), vec![x, xs]));
+        let nil = Expr::new(ExprT::Constr("Nil".to_string(), vec![]));
+        assert_eq!(
+            ast,
+            vec![
+                symbol("a", 1),
+                list(vec![symbol("f", 1), Expr::new(ExprT::Literal(Literal::Integer(5)))], 1),
+                cons(symbol("f", 1), cons(var("x"), Expr::new(ExprT::Builtin("_append".to_string(), vec![var("xs"), nil])))),
+            ]
+        );
+        let tokens = Lexer::new("(quote (a b)) `~@x", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse();
+        assert!(ast.is_err());
+        let tokens = Lexer::new("(quote (a b))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        assert_eq!(ast, vec![list(vec![symbol("a", 1), symbol("b", 1)], 1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn modules() -> Result<()> {
+        let tokens = Lexer::new("(module list (export map List)) (import list) (import list :as l (only map))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        assert_eq!(
+            ast,
+            vec![
+                Expr::new(ExprT::Module("list".to_string(), vec!["map".to_string(), "List".to_string()])),
+                Expr::new(ExprT::Import("list".to_string(), None, None)),
+                Expr::new(ExprT::Import("list".to_string(), Some("l".to_string()), Some(vec!["map".to_string()]))),
+            ]
+        );
+        let tokens = Lexer::new("(import a.b)", 0).proc_tokens()?;
+        assert!(Parser::new(tokens, "TEST").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reader() -> Result<()> {
+        let tokens = Lexer::new("(reader #{} pairs) #{a 1} #\"b\"", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let var = |v: &str| Expr::new(ExprT::Var(v.to_string()));
+        let string = Expr::new(ExprT::Literal(Literal::String("b".to_string())));
+        assert_eq!(
+            ast,
+            vec![
+                Expr::new(ExprT::Reader("#{".to_string(), "pairs".to_string())),
+                Expr::new(ExprT::Call(Box::new(var("#{")), vec![var("a"), Expr::new(ExprT::Literal(Literal::Integer(1)))])),
+                Expr::new(ExprT::Call(Box::new(var("#\"")), vec![string])),
+            ]
+        );
         Ok(())
     }
 
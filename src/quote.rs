@@ -0,0 +1,212 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{
+    error,
+    lexer::{Lexer, TType, Token},
+    parser::{Expr, ExprT, Literal, MacroParam, Pattern},
+    vm::Value,
+    Result,
+};
+
+// Quoted code is made of Integers, Singles, Strings, `(Symbol name)`s and Lists of quoted code.
+
+pub fn symbol(name: impl ToString, line: usize) -> Expr {
+    Expr::new(ExprT::Constr("Symbol".to_string(), vec![Expr::new(ExprT::Literal(Literal::String(name.to_string()))).line(line)])).line(line)
+}
+
+pub fn list(items: Vec<Expr>, line: usize) -> Expr {
+    items.into_iter().rev().fold(Expr::new(ExprT::Constr("Nil".to_string(), vec![])).line(line), |acc, item| {
+        Expr::new(ExprT::Constr("Cons".to_string(), vec![item, acc])).line(line)
+    })
+}
+
+pub fn quote_list(exprs: &[Expr], line: usize) -> Expr {
+    list(exprs.iter().map(quote).collect(), line)
+}
+
+pub fn form(head: impl ToString, items: Vec<Expr>, line: usize) -> Expr {
+    let mut to_ret = vec![symbol(head, line)];
+    to_ret.extend(items);
+    list(to_ret, line)
+}
+
+fn quote_literal(lit: &Literal, line: usize) -> Expr {
+    Expr::new(ExprT::Literal(lit.clone())).line(line)
+}
+
+fn quote_params(param: &MacroParam, line: usize) -> Expr {
+    match param {
+        MacroParam::Var(name) => symbol(name, line),
+        MacroParam::List(params, rest) => {
+            let mut items = params.iter().map(|p| quote_params(p, line)).collect::<Vec<_>>();
+            if let Some(rest) = rest {
+                items.push(symbol("&", line));
+                items.push(symbol(rest, line));
+            }
+            list(items, line)
+        }
+    }
+}
+
+fn quote_pattern(pat: &Pattern, line: usize) -> Expr {
+    let quote_all = |pats: &[Pattern]| pats.iter().map(|p| quote_pattern(p, line)).collect::<Vec<_>>();
+    match pat {
+        Pattern::Var(name) => symbol(name, line),
+        Pattern::Constr(name, pats) if pats.is_empty() => symbol(name, line),
+        Pattern::Constr(name, pats) => form(name, quote_all(pats), line),
+        Pattern::Tuple(pats) if pats.is_empty() => list(vec![], line),
+        Pattern::Tuple(pats) => form(",", quote_all(pats), line),
+        Pattern::Literal(lit) => quote_literal(lit, line),
+        Pattern::Or(pats) => form("|", quote_all(pats), line),
+        Pattern::As(name, pat) => form("@", vec![symbol(name, line), quote_pattern(pat, line)], line),
+        Pattern::Range(start, end) => form("..", vec![quote_literal(start, line), quote_literal(end, line)], line),
+        Pattern::Prefix(prefix, rest) => form("++", vec![quote_literal(&Literal::String(prefix.to_string()), line), quote_pattern(rest, line)], line),
+        Pattern::Pin(name) => form("^", vec![symbol(name, line)], line),
+    }
+}
+
+// Returns an expression that builds the quoted code of `expr`.
+pub fn quote(expr: &Expr) -> Expr {
+    let line = expr.line;
+    let quote_all = |exprs: &[Expr]| exprs.iter().map(quote).collect::<Vec<_>>();
+    match &expr.exprt {
+        ExprT::Var(name) => symbol(name, line),
+        ExprT::Literal(lit) => quote_literal(lit, line),
+        ExprT::Call(func, args) => {
+            let mut items = vec![quote(func)];
+            items.extend(quote_all(args));
+            list(items, line)
+        }
+        ExprT::Lambda(args, body) => form("λ", vec![list(args.iter().map(|a| symbol(a, line)).collect(), line), quote(body)], line),
+        ExprT::Def(name, value, impure) => {
+            let mut items = if *impure { vec![symbol("'impure", line)] } else { vec![] };
+            items.push(symbol(name, line));
+            items.push(quote(value));
+            form("def", items, line)
+        }
+        ExprT::Macro(name, clauses) => {
+            let mut items = vec![symbol(name, line)];
+            items.extend(clauses.iter().map(|(params, body)| list(vec![quote_params(params, line), quote(body)], line)));
+            form("macro", items, line)
+        }
+        ExprT::ProcMacro(name, params, body) => form("defmacro", vec![symbol(name, line), quote_params(params, line), quote(body)], line),
+        ExprT::Reader(dispatch, name) => {
+            let closing = match dispatch.as_str() {
+                "#(" => ")",
+                "#[" => "]",
+                "#{" => "}",
+                _ => "\"",
+            };
+            form("reader", vec![symbol(format!("{}{}", dispatch, closing), line), symbol(name, line)], line)
+        }
+        ExprT::Constr(name, args) if args.is_empty() => symbol(name, line),
+        ExprT::Constr(name, args) => form(name, quote_all(args), line),
+        ExprT::Enum(name, variants) => {
+            let mut variants = variants.iter().collect::<Vec<_>>();
+            variants.sort();
+            let mut items = vec![symbol(name, line)];
+            items.extend(variants.into_iter().map(|(variant, amount)| if *amount == 0 {
+                symbol(variant, line)
+            } else {
+                form(variant, (0..*amount).map(|i| symbol(format!("x{}", i), line)).collect(), line)
+            }));
+            form("enum", items, line)
+        }
+        ExprT::Tuple(args) if args.is_empty() => list(vec![], line),
+        ExprT::Tuple(args) => form(",", quote_all(args), line),
+        ExprT::Load(files) => form("load", files.iter().map(|f| quote_literal(&Literal::String(f.to_string()), line)).collect(), line),
+        ExprT::Module(name, exports) => form("module", vec![symbol(name, line), form("export", exports.iter().map(|e| symbol(e, line)).collect(), line)], line),
+        ExprT::Import(name, alias, only) => {
+            let mut items = vec![symbol(name, line)];
+            if let Some(alias) = alias {
+                items.push(symbol(":as", line));
+                items.push(symbol(alias, line));
+            }
+            if let Some(only) = only {
+                items.push(form("only", only.iter().map(|n| symbol(n, line)).collect(), line));
+            }
+            form("import", items, line)
+        }
+        ExprT::Match(value, arms) => {
+            let mut items = vec![quote(value)];
+            items.extend(arms.iter().map(|(pat, arm)| list(vec![quote_pattern(pat, line), quote(arm)], line)));
+            form("match", items, line)
+        }
+        ExprT::Begin(exprs) => form("begin", quote_all(exprs), line),
+        ExprT::Builtin(name, args) => form(name, quote_all(args), line),
+    }
+}
+
+// Turns quoted code back into tokens, `names` being the name of every constructor.
+pub fn unquote(val: &Value, names: &[String], file: &str, line: usize) -> Result<Vec<Token>> {
+    let token = |ttype| Token::new(ttype, line);
+    Ok(match val {
+        Value::Integer(i) => vec![token(TType::Number(*i))],
+        Value::Single(f) => vec![token(TType::Float(*f))],
+        Value::String(s) => vec![token(TType::Str(s.to_string()))],
+        Value::Lambda(..) => return error!(file, line => "Cannot convert a Function to code."),
+        Value::Tuple(vals) if vals.is_empty() => vec![token(TType::LParen), token(TType::RParen)],
+        Value::Tuple(vals) => {
+            let mut to_ret = vec![token(TType::LParen), token(TType::Tuple)];
+            for val in vals {
+                to_ret.extend(unquote(val, names, file, line)?);
+            }
+            to_ret.push(token(TType::RParen));
+            to_ret
+        }
+        Value::Constructor(id, vals) => match (names[*id as usize].as_str(), vals.as_slice()) {
+            ("Symbol", [name]) => match &**name {
+                // The head of an expanded reader macro, that is not a token on its own.
+                Value::String(name) if ["#(", "#[", "#{", "#\""].contains(&&**name) => vec![token(TType::Ident(name.to_string()))],
+                Value::String(name) => Lexer::new(name, file).line(line).proc_tokens()?,
+                _ => return error!(file, line => "Expected a String as Symbol name."),
+            }
+            ("Cons", _) | ("Nil", _) => {
+                let mut to_ret = vec![token(TType::LParen)];
+                let mut current = val;
+                while let Value::Constructor(id, vals) = current {
+                    match (names[*id as usize].as_str(), vals.as_slice()) {
+                        ("Cons", [item, next]) => {
+                            to_ret.extend(unquote(item, names, file, line)?);
+                            current = &**next;
+                        }
+                        ("Nil", []) => break,
+                        _ => return error!(file, line => "Expected a List in quoted code."),
+                    }
+                }
+                if !matches!(current, Value::Constructor(..)) {
+                    return error!(file, line => "Expected a List in quoted code.");
+                }
+                to_ret.push(token(TType::RParen));
+                to_ret
+            }
+            (name, []) => vec![token(TType::Ident(name.to_string()))],
+            (name, vals) => {
+                let mut to_ret = vec![token(TType::LParen), token(TType::Ident(name.to_string()))];
+                for val in vals {
+                    to_ret.extend(unquote(val, names, file, line)?);
+                }
+                to_ret.push(token(TType::RParen));
+                to_ret
+            }
+        }
+    })
+}
@@ -0,0 +1,225 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{bug, bytecode::{Capture, Slot}};
+
+// The constructs that open a scope.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScopeKind {
+    Function,
+    Begin,
+    Block, // A `begin` block run in the frame of the enclosing function, once optimized.
+    Arm,
+}
+
+impl std::fmt::Display for ScopeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            ScopeKind::Function => "function",
+            ScopeKind::Begin | ScopeKind::Block => "begin block",
+            ScopeKind::Arm => "match arm",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Scope {
+    pub kind: ScopeKind,
+    pub parent: Option<usize>,
+    pub line: usize,
+    pub bindings: Vec<usize>,
+    pub slots: u16, // The size of the frame of functions and begin blocks.
+    pub captures: Vec<Capture>, // Their free variables, that their upvalues are captured from.
+}
+
+impl Scope {
+    // Whether the scope is run in a frame of its own.
+    pub fn is_frame(&self) -> bool {
+        matches!(self.kind, ScopeKind::Function | ScopeKind::Begin)
+    }
+}
+
+// A variable bound by a parameter, a pattern or a local definition. Each binding has its own slot in the frame of its scope, so that shadowed variables do not clash.
+#[derive(Clone, Debug)]
+pub struct Binding {
+    pub name: String,
+    pub slot: u16,
+    pub impure: bool,
+    pub scope: usize,
+}
+
+// The tree of the lexical scopes of the expressions compiled so far, the top-level being the root.
+#[derive(Clone, Debug, Default)]
+pub struct Resolver {
+    pub scopes: Vec<Scope>,
+    pub bindings: Vec<Binding>,
+    current: Option<usize>,
+    top_slots: u16, // The size of the top-level frame, where the match arms out of any function bind their variables.
+}
+
+impl Resolver {
+    pub fn enter(&mut self, kind: ScopeKind, line: usize) {
+        self.scopes.push(Scope {
+            kind,
+            parent: self.current,
+            line,
+            bindings: vec![],
+            slots: 0,
+            captures: vec![],
+        });
+        self.current = Some(self.scopes.len() - 1);
+    }
+    pub fn exit(&mut self) {
+        self.current = self.current.and_then(|s| self.scopes[s].parent);
+    }
+    // Leaves all the scopes, while compiling a loaded file, and returns the current one to resume it afterwards.
+    pub fn suspend(&mut self) -> Option<usize> {
+        self.current.take()
+    }
+    pub fn resume(&mut self, scope: Option<usize>) {
+        self.current = scope;
+    }
+    pub fn is_top_level(&self) -> bool {
+        self.current.is_none()
+    }
+    // The innermost scope having a frame, the top-level frame being `None`.
+    fn frame(&self) -> Option<usize> {
+        let mut scope = self.current;
+        while let Some(s) = scope {
+            if self.scopes[s].is_frame() {
+                return Some(s);
+            }
+            scope = self.scopes[s].parent;
+        }
+        None
+    }
+    // The amount of slots of the current frame.
+    pub fn slots(&self) -> u16 {
+        self.frame().map_or(self.top_slots, |f| self.scopes[f].slots)
+    }
+    // The variables captured by the current frame.
+    pub fn captures(&self) -> Vec<Capture> {
+        self.frame().map_or(vec![], |f| self.scopes[f].captures.clone())
+    }
+    // Returns the upvalue of a frame that holds a capture, adding it if needed.
+    fn capture(&mut self, frame: usize, capture: Capture) -> u16 {
+        let captures = &mut self.scopes[frame].captures;
+        match captures.iter().position(|c| c == &capture) {
+            Some(idx) => idx as u16,
+            None => {
+                captures.push(capture);
+                captures.len() as u16 - 1
+            }
+        }
+    }
+    // Binds a name in the current scope, to a new slot of the current frame.
+    pub fn bind(&mut self, name: impl ToString, impure: bool) -> u16 {
+        let scope = match self.current {
+            Some(scope) => scope,
+            None => bug!("BINDING_OUT_OF_SCOPE"),
+        };
+        let slots = match self.frame() {
+            Some(frame) => &mut self.scopes[frame].slots,
+            None => &mut self.top_slots,
+        };
+        let slot = *slots;
+        *slots += 1;
+        self.bindings.push(Binding {
+            name: name.to_string(),
+            slot,
+            impure,
+            scope,
+        });
+        self.scopes[scope].bindings.push(self.bindings.len() - 1);
+        slot
+    }
+    // The binding of a name in the current scope only.
+    pub fn local(&self, name: &str) -> Option<&Binding> {
+        let scope = &self.scopes[self.current?];
+        scope.bindings.iter().rev().map(|b| &self.bindings[*b]).find(|b| b.name == name)
+    }
+    // The innermost binding of a name visible from the current scope, with the slot it is found in from the current frame.
+    // A binding of an enclosing frame is captured by all the frames in between.
+    pub fn lookup(&mut self, name: &str) -> Option<(Binding, Slot)> {
+        let mut scope = self.current;
+        let mut frames = vec![];
+        while let Some(s) = scope {
+            if let Some(binding) = self.scopes[s].bindings.iter().rev().map(|b| &self.bindings[*b]).find(|b| b.name == name).cloned() {
+                let slot = match frames.pop() {
+                    None => Slot::Local(binding.slot),
+                    Some(outermost) => {
+                        let mut idx = self.capture(outermost, Capture::Local(binding.slot));
+                        while let Some(frame) = frames.pop() {
+                            idx = self.capture(frame, Capture::Upvalue(idx));
+                        }
+                        Slot::Upvalue(idx)
+                    }
+                };
+                return Some((binding, slot));
+            }
+            if self.scopes[s].is_frame() {
+                frames.push(s);
+            }
+            scope = self.scopes[s].parent;
+        }
+        None
+    }
+    // A scope out of reach binding a name, to explain why it is not in scope.
+    pub fn elsewhere(&self, name: &str) -> Option<&Scope> {
+        self.bindings.iter().rev().find(|b| b.name == name).map(|b| &self.scopes[b.scope])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scopes() {
+        let mut resolver = Resolver::default();
+        assert!(resolver.is_top_level());
+        resolver.enter(ScopeKind::Function, 1);
+        resolver.bind("x", false);
+        resolver.bind("f", false);
+        resolver.enter(ScopeKind::Function, 1);
+        resolver.bind("x", false);
+        assert_eq!(resolver.lookup("x").unwrap().1, Slot::Local(0));
+        resolver.enter(ScopeKind::Begin, 1);
+        // Captured through the function, by its upvalue.
+        assert_eq!(resolver.lookup("f").unwrap().1, Slot::Upvalue(0));
+        assert_eq!(resolver.captures(), vec![Capture::Upvalue(0)]);
+        resolver.exit();
+        assert_eq!(resolver.lookup("f").unwrap().1, Slot::Upvalue(0));
+        assert_eq!(resolver.captures(), vec![Capture::Local(1)]);
+        resolver.enter(ScopeKind::Arm, 2);
+        // The variables of match arms live in the frame of the enclosing function.
+        assert_eq!(resolver.bind("y", false), 1);
+        assert!(resolver.local("x").is_none());
+        assert_eq!(resolver.lookup("x").unwrap().1, Slot::Local(0));
+        resolver.exit();
+        assert_eq!(resolver.slots(), 2);
+        assert!(resolver.lookup("y").is_none());
+        assert_eq!(resolver.elsewhere("y").unwrap().kind, ScopeKind::Arm);
+        resolver.exit();
+        assert_eq!(resolver.lookup("x").unwrap().1, Slot::Local(0));
+        resolver.exit();
+        assert!(resolver.is_top_level());
+    }
+}
@@ -0,0 +1,217 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::bytecode::{Bytecode, Chunk, Decision, Match, OpCode, Slot, Test};
+use std::rc::Rc;
+
+// The symbol defined by a top-level definition that can be dropped when unused, its value being computed without calling anything, nor reading a global not defined yet.
+fn removable(expression: &[OpCode], defined: &[bool]) -> Option<u16> {
+    match expression.split_last() {
+        Some((OpCode::Def(sym), value)) if value.iter().all(|op| match op {
+            OpCode::LoadConst(_) | OpCode::Lambda(_) | OpCode::Constructor(_) | OpCode::Tuple(_) => true,
+            OpCode::LoadGlobal(sym) => defined[*sym as usize],
+            _ => false,
+        }) => Some(*sym),
+        _ => None,
+    }
+}
+
+// What can be reached from the code kept in the bytecode.
+struct Reachable<'a> {
+    bytecode: &'a Bytecode,
+    definitions: Vec<Vec<&'a [OpCode]>>, // The removable definitions of each symbol.
+    symbols: Vec<bool>,
+    chunks: Vec<bool>,
+    constants: Vec<bool>,
+    matches: Vec<bool>,
+    constructors: Vec<bool>,
+    pending: Vec<&'a [OpCode]>,
+}
+
+impl<'a> Reachable<'a> {
+    fn global(&mut self, sym: u16) {
+        if !self.symbols[sym as usize] {
+            self.symbols[sym as usize] = true;
+            self.pending.extend(self.definitions[sym as usize].iter().copied());
+        }
+    }
+    fn decision(&mut self, tree: &Decision) {
+        if let Decision::Switch(_, cases, default) = tree {
+            for (test, _, subtree) in cases {
+                match test {
+                    Test::Constr(idx) => self.constructors[*idx as usize] = true,
+                    Test::Literal(id) | Test::Prefix(id) => self.constants[*id as usize] = true,
+                    Test::Range(start, end) => {
+                        self.constants[*start as usize] = true;
+                        self.constants[*end as usize] = true;
+                    }
+                    Test::Pin(Slot::Global(sym)) => self.global(*sym),
+                    Test::Tuple(_) | Test::Pin(_) => {}
+                }
+                self.decision(subtree);
+            }
+            self.decision(default);
+        }
+    }
+    fn visit(&mut self) {
+        let bytecode = self.bytecode;
+        while let Some(code) = self.pending.pop() {
+            for op in code {
+                match *op {
+                    OpCode::LoadGlobal(sym) => self.global(sym),
+                    OpCode::LoadConst(id) => self.constants[id as usize] = true,
+                    OpCode::Panic(file, line) => {
+                        self.constants[file as usize] = true;
+                        self.constants[line as usize] = true;
+                    }
+                    OpCode::Constructor(idx) => self.constructors[idx as usize] = true,
+                    OpCode::Lambda(chunk) if !self.chunks[chunk as usize] => {
+                        self.chunks[chunk as usize] = true;
+                        self.pending.push(&bytecode.chunks[chunk as usize].instructions);
+                    }
+                    OpCode::Match(idx) if !self.matches[idx as usize] => {
+                        self.matches[idx as usize] = true;
+                        self.decision(&bytecode.matches[idx as usize].tree);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// The new ids of the kept items, in their order.
+fn renumber(kept: &[bool]) -> Vec<u16> {
+    kept.iter().scan(0, |next, keep| {
+        let id = *next;
+        *next += *keep as u16;
+        Some(id)
+    }).collect()
+}
+
+fn rewrite(code: &[OpCode], chunks: &[u16], constants: &[u16], matches: &[u16], constructors: &[u16]) -> Vec<OpCode> {
+    code.iter().map(|op| match *op {
+        OpCode::LoadConst(id) => OpCode::LoadConst(constants[id as usize]),
+        OpCode::Panic(file, line) => OpCode::Panic(constants[file as usize], constants[line as usize]),
+        OpCode::Lambda(chunk) => OpCode::Lambda(chunks[chunk as usize]),
+        OpCode::Match(idx) => OpCode::Match(matches[idx as usize]),
+        OpCode::Constructor(idx) => OpCode::Constructor(constructors[idx as usize]),
+        op => op,
+    }).collect()
+}
+
+fn rewrite_decision(tree: &Decision, constants: &[u16], constructors: &[u16]) -> Decision {
+    match tree {
+        Decision::Switch(occurrence, cases, default) => Decision::Switch(
+            *occurrence,
+            cases.iter().map(|(test, child, subtree)| (match test {
+                Test::Constr(idx) => Test::Constr(constructors[*idx as usize]),
+                Test::Literal(id) => Test::Literal(constants[*id as usize]),
+                Test::Prefix(id) => Test::Prefix(constants[*id as usize]),
+                Test::Range(start, end) => Test::Range(constants[*start as usize], constants[*end as usize]),
+                test => test.clone(),
+            }, *child, rewrite_decision(subtree, constants, constructors))).collect(),
+            Box::new(rewrite_decision(default, constants, constructors)),
+        ),
+        tree => tree.clone(),
+    }
+}
+
+// Drops the top-level definitions that cannot be reached from the other top-level expressions and from the call to `main`, with the chunks, constants, matches and types only they use.
+// `expressions` is the code of each top-level expression, that the instructions start with. The programs using `eval` are kept whole, as the code it runs may use any definition.
+pub fn shake(bytecode: Bytecode, expressions: &[Vec<OpCode>]) -> Bytecode {
+    let length = expressions.iter().map(Vec::len).sum::<usize>();
+    let evaluates = bytecode.instructions.iter().chain(bytecode.chunks.iter().flat_map(|c| c.instructions.iter())).any(|op| op == &OpCode::Eval);
+    if evaluates || bytecode.instructions.len() < length || !expressions.iter().flatten().eq(&bytecode.instructions[..length]) {
+        return bytecode;
+    }
+
+    let mut defined = vec![false; bytecode.symbols.len()];
+    let mut definitions = vec![vec![]; bytecode.symbols.len()];
+    let mut roots = vec![];
+    let mut removed = vec![];
+    for expression in expressions {
+        match removable(expression, &defined) {
+            Some(sym) => {
+                definitions[sym as usize].push(expression.as_slice());
+                removed.push(Some(sym));
+            }
+            None => {
+                roots.push(expression.as_slice());
+                removed.push(None);
+            }
+        }
+        if let Some(OpCode::Def(sym)) = expression.last() {
+            defined[*sym as usize] = true;
+        }
+    }
+    roots.push(&bytecode.instructions[length..]);
+
+    let mut reachable = Reachable {
+        bytecode: &bytecode,
+        definitions,
+        symbols: vec![false; bytecode.symbols.len()],
+        chunks: vec![false; bytecode.chunks.len()],
+        constants: vec![false; bytecode.constants.len()],
+        matches: vec![false; bytecode.matches.len()],
+        constructors: vec![false; bytecode.constructors.len()],
+        pending: roots,
+    };
+    reachable.visit();
+    // A type is kept with all its constructors, that take contiguous ids.
+    let mut types = vec![];
+    for (name, start, end) in &bytecode.types {
+        if (*start..=*end).any(|idx| reachable.constructors[idx as usize]) {
+            (*start..=*end).for_each(|idx| reachable.constructors[idx as usize] = true);
+            types.push((name.clone(), *start, *end));
+        }
+    }
+
+    let chunks = renumber(&reachable.chunks);
+    let constants = renumber(&reachable.constants);
+    let matches = renumber(&reachable.matches);
+    let constructors = renumber(&reachable.constructors);
+    let mut instructions = expressions
+        .iter()
+        .zip(removed)
+        .filter(|(_, sym)| match sym {
+            Some(sym) => reachable.symbols[*sym as usize],
+            None => true,
+        })
+        .flat_map(|(expression, _)| rewrite(expression, &chunks, &constants, &matches, &constructors))
+        .collect::<Vec<OpCode>>();
+    instructions.extend(rewrite(&bytecode.instructions[length..], &chunks, &constants, &matches, &constructors));
+
+    Bytecode {
+        types: types.into_iter().map(|(name, start, end)| (name, constructors[start as usize], constructors[end as usize])).collect(),
+        chunks: bytecode.chunks.iter().zip(&reachable.chunks).filter(|(_, keep)| **keep).map(|(chunk, _)| Chunk {
+            instructions: rewrite(&chunk.instructions, &chunks, &constants, &matches, &constructors).into(),
+            ..chunk.clone()
+        }).collect(),
+        matches: bytecode.matches.iter().zip(&reachable.matches).filter(|(_, keep)| **keep).map(|(m, _)| Rc::new(Match {
+            tree: rewrite_decision(&m.tree, &constants, &constructors),
+            ..(**m).clone()
+        })).collect(),
+        symbols: bytecode.symbols.clone(),
+        constants: bytecode.constants.iter().zip(&reachable.constants).filter(|(_, keep)| **keep).map(|(c, _)| c.clone()).collect(),
+        instructions,
+        constructors: bytecode.constructors.iter().zip(&reachable.constructors).filter(|(_, keep)| **keep).map(|(c, _)| *c).collect(),
+    }
+}
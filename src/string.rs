@@ -21,21 +21,178 @@
 use crate::{vm::{VM, Value}, error, Result};
 use std::rc::Rc;
 
+enum Argument {
+    Next,
+    Index(usize),
+    Name(String), // Looked up in the `(, name value)` arguments.
+}
+
+// A `{argument:spec}` placeholder, the spec being `[[fill]align][0][width][.precision][type]`.
+struct Placeholder {
+    argument: Argument,
+    fill: char,
+    align: Option<char>,
+    zero: bool, // Pads numbers with zeros after their sign.
+    width: usize,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+// The largest width and precision, so that a placeholder neither allocates without bound nor overflows `format!`.
+const MAX_SPEC: usize = u16::MAX as usize;
+
+fn placeholder(content: &str) -> Result<Placeholder> {
+    let (argument, spec) = match content.find(':') {
+        Some(idx) => (&content[..idx], &content[idx + 1..]),
+        None => (content, ""),
+    };
+    let argument = if argument.is_empty() {
+        Argument::Next
+    } else if let Ok(idx) = argument.parse::<usize>() {
+        Argument::Index(idx)
+    } else {
+        Argument::Name(argument.to_string())
+    };
+
+    let aligns = ['<', '>', '^'];
+    let mut chars = spec.chars().peekable();
+    let (fill, align) = match (spec.chars().next(), spec.chars().nth(1)) {
+        (Some(fill), Some(align)) if aligns.contains(&align) => {
+            chars.nth(1);
+            (fill, Some(align))
+        }
+        (Some(align), _) if aligns.contains(&align) => {
+            chars.next();
+            (' ', Some(align))
+        }
+        _ => (' ', None),
+    };
+    let number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        let mut digits = String::new();
+        while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            digits.push(*c);
+            chars.next();
+        }
+        if digits.is_empty() {
+            return Ok(None);
+        }
+        match digits.parse::<usize>() {
+            Ok(n) if n <= MAX_SPEC => Ok(Some(n)),
+            _ => error!(=> "Width or precision {} is larger than {} in format placeholder {{{}}}.", digits, MAX_SPEC, content),
+        }
+    };
+    let zero = chars.peek() == Some(&'0');
+    if zero {
+        chars.next();
+    }
+    let width = number(&mut chars)?.unwrap_or(0);
+    let precision = if chars.peek() == Some(&'.') {
+        chars.next();
+        match number(&mut chars)? {
+            Some(p) => Some(p),
+            None => return error!(=> "Expected a precision after `.` in format placeholder {{{}}}.", content),
+        }
+    } else {
+        None
+    };
+    let kind = chars.next();
+    if let Some(k) = kind {
+        if !['x', 'X', 'b', 'o'].contains(&k) || chars.next().is_some() {
+            return error!(=> "Invalid format placeholder: {{{}}}.", content);
+        }
+    }
+    Ok(Placeholder { argument, fill, align, zero, width, precision, kind })
+}
+
 impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
+    fn render(&mut self, placeholder: &Placeholder, val: Rc<Value>) -> Result<String> {
+        let text = match (placeholder.kind, placeholder.precision, &*val) {
+            (Some('x'), None, Value::Integer(i)) => format!("{:x}", i),
+            (Some('X'), None, Value::Integer(i)) => format!("{:X}", i),
+            (Some('b'), None, Value::Integer(i)) => format!("{:b}", i),
+            (Some('o'), None, Value::Integer(i)) => format!("{:o}", i),
+            (Some(_), None, _) => return error!(=> "Expected an Integer, found a {}.", self.val_type(&val)?),
+            (None, Some(p), Value::Single(f)) => format!("{:.*}", p, f),
+            (None, Some(p), Value::String(s)) => s.chars().take(p).collect(),
+            (_, Some(_), _) => return error!(=> "Expected a Single or a String, found a {}.", self.val_type(&val)?),
+            (None, None, _) => self.display_value(val.clone(), false),
+        };
+        let padding = placeholder.width.saturating_sub(text.chars().count());
+        let numeric = matches!(&*val, Value::Integer(_) | Value::Single(_));
+        if placeholder.zero && numeric {
+            let (sign, digits) = text.split_at(if text.starts_with('-') { 1 } else { 0 });
+            return Ok(format!("{}{}{}", sign, "0".repeat(padding), digits));
+        }
+        let (left, right) = match placeholder.align {
+            Some('<') => (0, padding),
+            Some('^') => (padding / 2, padding - padding / 2),
+            Some(_) => (padding, 0),
+            None if numeric => (padding, 0),
+            None => (0, padding),
+        };
+        let fill = |n| placeholder.fill.to_string().repeat(n);
+        Ok(format!("{}{}{}", fill(left), text, fill(right)))
+    }
     pub fn format(&mut self) -> Result<Rc<Value>> {
         let args = self.pop()?;
         let formatter = self.pop()?;
-        if let Value::Tuple(args) = (*args).clone() {
-            if let Value::String(formatter) = (*formatter).clone() {
-                let fmt = "{}";
-                let mut prev = 0;
-                let to_ret = formatter.match_indices(fmt).enumerate().map(|(idx, (pos, _))| {
-                    let to_ret = format!("{}{}", &formatter[prev..pos], self.display_value(args[idx].clone(), false));
-                    prev = pos + fmt.len();
-                    to_ret
-                }).collect::<Vec<String>>().join("");
+        if let Value::Tuple(args) = &*args {
+            if let Value::String(formatter) = &*formatter {
+                let mut to_ret = String::new();
+                let mut used = vec![false; args.len()];
+                let mut next = 0;
+                let mut chars = formatter.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '{' if chars.peek() == Some(&'{') => {
+                            chars.next();
+                            to_ret.push('{');
+                        }
+                        '}' if chars.peek() == Some(&'}') => {
+                            chars.next();
+                            to_ret.push('}');
+                        }
+                        '}' => return error!(=> "Unmatched `}}` in format string, use `}}}}` to write a literal `}}`."),
+                        '{' => {
+                            let mut content = String::new();
+                            loop {
+                                match chars.next() {
+                                    Some('}') => break,
+                                    Some(c) => content.push(c),
+                                    None => return error!(=> "Unterminated format placeholder, use `{{{{` to write a literal `{{`."),
+                                }
+                            }
+                            let placeholder = placeholder(&content)?;
+                            let idx = match &placeholder.argument {
+                                Argument::Next => {
+                                    next += 1;
+                                    next - 1
+                                }
+                                Argument::Index(idx) => *idx,
+                                Argument::Name(name) => match args.iter().position(|arg| matches!(&**arg, Value::Tuple(pair) if pair.len() == 2 && matches!(&*pair[0], Value::String(s) if **s == **name))) {
+                                    Some(idx) => idx,
+                                    None => return error!(=> "No argument named {} was given to format.", name),
+                                }
+                            };
+                            if idx >= args.len() {
+                                return error!(=> "Format string uses argument {} but only {} arguments were given.", idx, args.len());
+                            }
+                            used[idx] = true;
+                            let val = match (&placeholder.argument, &*args[idx]) {
+                                (Argument::Name(_), Value::Tuple(pair)) => pair[1].clone(),
+                                _ => args[idx].clone(),
+                            };
+                            to_ret.push_str(&self.render(&placeholder, val)?);
+                        }
+                        c => to_ret.push(c),
+                    }
+                }
+                let unused = used.iter().filter(|u| !**u).count();
+                if unused > 0 {
+                    return error!(=> "Format string uses {} of the {} arguments given.", args.len() - unused, args.len());
+                }
 
-                Ok(Rc::new(Value::String(format!("{}{}", to_ret, &formatter[prev..]))))
+                Ok(Rc::new(Value::String(to_ret.into())))
             } else {
                 error!(=> "Expected a String, found a {}.", self.val_type(&formatter)?)
             }
@@ -46,12 +203,12 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
     pub fn get(&mut self) -> Result<Rc<Value>> {
         let idx = self.pop()?;
         let string = self.pop()?;
-        if let Value::Integer(i) = (*idx).clone() {
-            if let Value::String(s) = (*string).clone() {
+        if let Value::Integer(i) = *idx {
+            if let Value::String(s) = &*string {
                 Ok(Rc::new(Value::String(if i < 0 {
-                    "".to_string()
+                    "".into()
                 } else {
-                    s.chars().nth(i as usize).and_then(|c| Some(format!("{}", c))).unwrap_or("".to_string())
+                    s.chars().nth(i as usize).map(|c| c.to_string()).unwrap_or_default().into()
                 })))
             } else {
                 error!(=> "Expected a String, found a {}.", self.val_type(&string)?)
@@ -19,44 +19,160 @@
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::{    
-    bytecode::{Bytecode, BytecodePattern, OpCode},
-    error, bug,
-    parser::Literal,
+    bug,
+    bytecode::{Bytecode, Capture, Chunk, Decision, OpCode, Slot, Test},
+    compiler::{Compiler, Macro},
+    error,
+    gc::{Heap, Stats},
+    modules::{Module, Namespace},
+    parser::{Literal, Parser},
+    quote::unquote,
     Result,
 };
 use std::io::{self, Write};
 
-use std::rc::Rc;
+use std::{cell::RefCell, rc::Rc, time::{Duration, Instant}};
 
 #[derive(Debug, Clone)]
 pub enum Value {
     Integer(i32),
     Single(f32),
-    String(String),
-    Lambda(u16, u16, Vec<u16>),
+    String(Rc<str>),
+    Lambda(u16, Rc<[Cell]>), // (chunk_id, upvalues)
     Constructor(u16, Vec<Rc<Value>>),
     Tuple(Vec<Rc<Value>>),
 }
 
+// Frees the values owned by a value from a worklist, as dropping a long list recursively would overflow the native stack.
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut pending = match self {
+            Value::Constructor(_, values) | Value::Tuple(values) if !values.is_empty() => std::mem::take(values),
+            _ => return,
+        };
+        while let Some(value) = pending.pop() {
+            if let Ok(Value::Constructor(_, values) | Value::Tuple(values)) = Rc::try_unwrap(value).as_mut() {
+                pending.append(values);
+            }
+        }
+    }
+}
+
+// What is left to display of a value or of its type, the values being displayed with or without quotes.
+enum Part<'a> {
+    Value(&'a Value, bool),
+    Text(&'static str),
+}
+
+// The top-level definitions, by symbol.
+pub type Globals = Vec<Option<Rc<Value>>>;
+
+// A variable shared by the frame binding it and the functions capturing it.
+pub type Cell = Rc<RefCell<Rc<Value>>>;
+
+#[derive(Debug, Clone)]
+pub enum Local {
+    Value(Rc<Value>),
+    Cell(Cell), // Once captured.
+}
+
+// The slots of the function being run, and the upvalues it captured when it was created.
+#[derive(Debug, Clone, Default)]
+pub struct Frame {
+    pub locals: Vec<Local>,
+    pub upvalues: Rc<[Cell]>, // Shared with the closure being run.
+}
+
+impl Frame {
+    fn get(&self, slot: u16) -> Option<Rc<Value>> {
+        self.locals.get(slot as usize).map(|local| match local {
+            Local::Value(value) => value.clone(),
+            Local::Cell(cell) => cell.borrow().clone(),
+        })
+    }
+    // Stores a value in a slot, the top-level frame growing as its match arms bind variables.
+    fn set(&mut self, slot: u16, value: Rc<Value>) {
+        if self.locals.len() <= slot as usize {
+            self.locals.resize(slot as usize + 1, Local::Value(Rc::new(Value::Tuple(vec![]))));
+        }
+        match &self.locals[slot as usize] {
+            Local::Cell(cell) => *cell.borrow_mut() = value,
+            Local::Value(_) => self.locals[slot as usize] = Local::Value(value),
+        }
+    }
+    // Turns a slot into a cell, so that a function defined in the frame sees the value it is later set to, as its own one when it is recursive.
+    fn capture(&mut self, slot: u16, heap: &mut Heap) -> Cell {
+        if self.locals.len() <= slot as usize {
+            self.set(slot, Rc::new(Value::Tuple(vec![])));
+        }
+        match &self.locals[slot as usize] {
+            Local::Cell(cell) => cell.clone(),
+            Local::Value(value) => {
+                let cell = heap.cell(value.clone());
+                self.locals[slot as usize] = Local::Cell(cell.clone());
+                cell
+            }
+        }
+    }
+}
+
+// The resources a program may use before being stopped, so that untrusted code always terminates.
+// The stack and the call depth are bounded by the size of the VM by default, the other ones are unbounded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub stack: Option<usize>, // The values on the operand stack.
+    pub depth: Option<usize>, // The functions being run.
+    pub fuel: Option<u64>, // The instructions run.
+    pub timeout: Option<Duration>,
+    pub memory: Option<usize>, // The bytes allocated, approximately, including the ones freed since.
+}
+
+// The approximate amount of bytes allocated to create a value, its elements being counted when created.
+fn size(value: &Value) -> usize {
+    std::mem::size_of::<Value>() + match value {
+        Value::String(s) => s.len(),
+        Value::Lambda(_, upvalues) => upvalues.len() * std::mem::size_of::<Cell>(),
+        Value::Constructor(_, vals) | Value::Tuple(vals) => vals.len() * std::mem::size_of::<Rc<Value>>(),
+        Value::Integer(_) | Value::Single(_) => 0,
+    }
+}
+
+// A chunk being run, its instructions being the top-level ones if it is `None`.
+struct CallFrame {
+    chunk: Option<u16>,
+    ip: usize,
+    frame: Frame,
+    height: Option<usize>, // The height of the stack before running code given to `eval`.
+}
+
 pub struct VM<const STACK_SIZE: usize> {
     pub input: Bytecode,
     pub stack: Vec<Rc<Value>>,
-    saves: Vec<Vec<Rc<Value>>>,
     pub builtins: Vec<(
         fn(&mut VM<STACK_SIZE>) -> Result<Rc<Value>>,
         u8,
         )>,
-        pub ip: usize,
+        frames: Vec<CallFrame>, // The functions being run, the innermost one last.
+        globals: Globals,
+        pub macros: Vec<(String, Macro)>, // Available to the code run by `eval`.
+        pub namespaces: (Vec<Module>, Namespace), // Likewise.
+        constants: Vec<Rc<Value>>, // The values of the constants loaded so far, shared by all their loads.
+        unit: Rc<Value>,
+        limits: Limits,
+        pub heap: Heap, // The cells of the closures, kept between the lines of the REPL.
+        steps: u64, // The instructions run so far.
+        allocated: usize,
+        deadline: Option<Instant>,
 }
 fn to_val(lit: &Literal) -> Value {
     match lit {
         Literal::Integer(i) => Value::Integer(*i),
         Literal::Single(s) => Value::Single(*s),
-        Literal::String(s) => Value::String(s.to_string()),
+        Literal::String(s) => Value::String(s.as_str().into()),
     }
 }
 impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
-    pub fn new(input: Bytecode, saves: Vec<Vec<Rc<Value>>>) -> Self {
+    pub fn new(input: Bytecode) -> Self {
         let mut to_ret = Self {
             input,
             stack: {
@@ -65,8 +181,17 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
                 stack
             },
             builtins: vec![],
-            saves,
-            ip: 0,
+            frames: vec![],
+            globals: vec![],
+            macros: vec![],
+            namespaces: (vec![], Namespace::default()),
+            constants: vec![],
+            unit: Rc::new(Value::Tuple(vec![])),
+            limits: Limits::default(),
+            heap: Heap::default(),
+            steps: 0,
+            allocated: 0,
+            deadline: None,
         };
         to_ret.register_builtin(Self::add, 2);
         to_ret.register_builtin(Self::sub, 2);
@@ -88,99 +213,108 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
 
         to_ret.register_builtin(Self::r#type, 1);
         to_ret.register_builtin(Self::cmp, 2);
+        to_ret.register_builtin(Self::append, 2);
         to_ret
     }
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Self {
+            limits,
+            ..self
+        }
+    }
+    // Values are displayed from a worklist rather than recursively, so that long lists do not overflow the native stack.
     pub fn display_value(&self, val: Rc<Value>, quotes: bool) -> String {
-
-        match &*val {
-            Value::Integer(i) => format!("{}", i),
-            Value::Single(r) => format!("{}{}", r, if r.fract() == 0.0 { "." } else { "" }),
-            Value::String(s) => format!("{}{}{}", if quotes { "\"" } else { "" }, s, if quotes { "\"" } else { "" }),
-            Value::Lambda(u, ..) => format!("λ{}", u),
-            Value::Constructor(id, args) => {
-                let name = self.input.symbols[self.input.constructors[*id as usize].1 as usize].clone();
-                if args.len() == 0 {
-                    name
-                } else {
-                    format!( "({} {})", self.input.symbols[self.input.constructors[*id as usize].1 as usize], args.into_iter().map(|a| format!("{}", self.display_value(a.clone(), true))).fold("".to_string(), |acc, c| format!("{}{}{}", acc, if acc.as_str() == "" { "" } else { " " }, c)).trim())
-                }
-            }            
-            Value::Tuple(args) => format!("({})", args.into_iter().map(|a| format!("{}", self.display_value(a.clone(), true))).fold("".to_string(), |acc, c| format!("{}{}{}", acc, if acc.as_str() == "" { "" } else { " " }, c)).trim()),
+        let mut to_ret = String::new();
+        let mut pending = vec![Part::Value(&val, quotes)];
+        while let Some(part) = pending.pop() {
+            match part {
+                Part::Text(text) => to_ret.push_str(text),
+                Part::Value(value, quotes) => match value {
+                    Value::Integer(i) => to_ret.push_str(&i.to_string()),
+                    Value::Single(r) => to_ret.push_str(&format!("{}{}", r, if r.fract() == 0.0 { "." } else { "" })),
+                    Value::String(s) if quotes => to_ret.push_str(&format!("\"{}\"", s)),
+                    Value::String(s) => to_ret.push_str(s),
+                    Value::Lambda(u, ..) => to_ret.push_str(&format!("λ{}", u)),
+                    Value::Constructor(id, args) => {
+                        let name = &self.input.symbols[self.input.constructors[*id as usize].1 as usize];
+                        if args.is_empty() {
+                            to_ret.push_str(name);
+                        } else {
+                            to_ret.push('(');
+                            to_ret.push_str(name);
+                            pending.push(Part::Text(")"));
+                            for arg in args.iter().rev() {
+                                pending.push(Part::Value(arg, true));
+                                pending.push(Part::Text(" "));
+                            }
+                        }
+                    }
+                    Value::Tuple(args) => {
+                        to_ret.push('(');
+                        pending.push(Part::Text(")"));
+                        for (idx, arg) in args.iter().enumerate().rev() {
+                            pending.push(Part::Value(arg, true));
+                            if idx > 0 {
+                                pending.push(Part::Text(" "));
+                            }
+                        }
+                    }
+                },
+            }
         }
+        to_ret
     }
+    // Compares the values pair by pair from a worklist, the first different pair deciding, so that long lists do not overflow the native stack.
     fn _cmp(&mut self, lhs: &Value, rhs: &Value) -> Result<std::cmp::Ordering> {
         use std::cmp::Ordering;
-        match lhs {
-            Value::Single(lhs) => match rhs {
-                Value::Single(rhs) => {
-                    Ok(lhs.partial_cmp(&rhs).unwrap())
+        let mut pending = vec![(lhs, rhs)];
+        while let Some((lhs, rhs)) = pending.pop() {
+            let ordering = match lhs {
+                Value::Single(lhs) => match rhs {
+                    Value::Single(rhs) => lhs.partial_cmp(rhs).unwrap(),
+                    _ => return error!(=> "Expected a Single, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a Single, found a {}.", self.val_type(&rhs)?),
-            }
-
-            Value::Integer(lhs) => match rhs {
-                Value::Integer(rhs) => {
-                    Ok(lhs.cmp(&rhs))
+                Value::Integer(lhs) => match rhs {
+                    Value::Integer(rhs) => lhs.cmp(rhs),
+                    _ => return error!(=> "Expected an Integer, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected an Integer, found a {}.", self.val_type(&rhs)?),
-            }
-            Value::String(lhs) => match rhs {
-                Value::String(rhs) => {
-                    Ok(lhs.cmp(&rhs))
+                Value::String(lhs) => match rhs {
+                    Value::String(rhs) => lhs.cmp(rhs),
+                    _ => return error!(=> "Expected a String, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a String, found a {}.", self.val_type(&rhs)?),
-            }
-            Value::Constructor(lid, vlhs) => match &rhs {
-                Value::Constructor(rid, vrhs) => {
-                    let tlhs = self.val_type(&lhs)?;
-                    let trhs = self.val_type(&rhs)?;
-                    if tlhs != trhs {
-                        error!(=> "Expected a {}, found a {}.", tlhs, trhs)
-                    } else {
-                        if lid != rid {
-                            error!(=> "Not the same enum variants, expected 0x{:04x}, found 0x{:04x}", lid, rid)
-                        } else {
-                            let mut to_ret = Ordering::Equal;
-
-                            for idx in 0..vlhs.len() {
-                                let lhs = &vlhs[idx];
-                                let rhs = &vrhs[idx];
-                                let res = self._cmp(lhs, rhs)?;
-                                if res != Ordering::Equal {
-                                    to_ret = res;
-                                    break;
-                                }
-                            }
-                            Ok(to_ret)
+                Value::Constructor(lid, vlhs) => match rhs {
+                    Value::Constructor(rid, vrhs) => {
+                        let tlhs = self.val_type(lhs)?;
+                        let trhs = self.val_type(rhs)?;
+                        if tlhs != trhs {
+                            return error!(=> "Expected a {}, found a {}.", tlhs, trhs);
+                        } else if lid != rid {
+                            return error!(=> "Not the same enum variants, expected 0x{:04x}, found 0x{:04x}", lid, rid);
                         }
+                        pending.extend(vlhs.iter().zip(vrhs).rev().map(|(lhs, rhs)| (&**lhs, &**rhs)));
+                        Ordering::Equal
                     }
+                    _ => return error!(=> "Expected a Constructor, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a Constructor, found a {}.", self.val_type(&rhs)?),
-            }
-            Value::Tuple(vlhs) => match rhs {
-                Value::Tuple(vrhs) => {
-                    let tlhs = self.val_type(&lhs)?;
-                    let trhs = self.val_type(&rhs)?;
-                    if tlhs != trhs {
-                        error!(=> "Expected a {}, found a {}.", tlhs, trhs)
-                    } else {
-                        let mut to_ret = Ordering::Equal;
-                        for idx in 0..vlhs.len() {
-                            let lhs = &vlhs[idx];
-                            let rhs = &vrhs[idx];
-                            let res = self._cmp(lhs, rhs)?;
-                            if res != Ordering::Equal {
-                                to_ret = res; 
-                                break;
-                            }
+                Value::Tuple(vlhs) => match rhs {
+                    Value::Tuple(vrhs) => {
+                        let tlhs = self.val_type(lhs)?;
+                        let trhs = self.val_type(rhs)?;
+                        if tlhs != trhs {
+                            return error!(=> "Expected a {}, found a {}.", tlhs, trhs);
                         }
-                        Ok(to_ret)
+                        pending.extend(vlhs.iter().zip(vrhs).rev().map(|(lhs, rhs)| (&**lhs, &**rhs)));
+                        Ordering::Equal
                     }
+                    _ => return error!(=> "Expected a Tuple, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a Tuple, found a {}.", self.val_type(&rhs)?),
+                _ => return error!(=> "Expected a String, found a {}.", self.val_type(rhs)?),
+            };
+            if ordering != Ordering::Equal {
+                return Ok(ordering);
             }
-            _ => error!(=> "Expected a String, found a {}.", self.val_type(&rhs)?),
         }
+        Ok(Ordering::Equal)
     }
 
     fn cmp(&mut self) -> Result<Rc<Value>> {
@@ -191,24 +325,61 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
         let res = self._cmp(&lhs, &rhs)?;
         Ok(Rc::new(Value::Integer(correspondance.iter().position(|x| x == &res).unwrap() as i32)))
     }
+    // Splices a list in quasiquoted code, without depending on the `append` in scope.
+    fn append(&mut self) -> Result<Rc<Value>> {
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        let mut cells = vec![];
+        let mut current = &*lhs;
+        loop {
+            match current {
+                Value::Constructor(id, vals) => match (self.input.symbols[self.input.constructors[*id as usize].1 as usize].as_str(), vals.as_slice()) {
+                    ("Cons", [item, next]) => {
+                        cells.push((*id, item.clone()));
+                        current = &**next;
+                    }
+                    ("Nil", []) => break,
+                    _ => return error!(=> "Expected a List, found a {}.", self.val_type(current)?),
+                }
+                _ => return error!(=> "Expected a List, found a {}.", self.val_type(current)?),
+            }
+        }
+        let mut to_ret = rhs;
+        for (id, item) in cells.into_iter().rev() {
+            to_ret = self.alloc(Value::Constructor(id, vec![item, to_ret]))?;
+        }
+        Ok(to_ret)
+    }
     fn r#type(&mut self) -> Result<Rc<Value>> {
         let popped = self.pop()?;
-        let to_ret = Ok(Rc::new(Value::String(self.val_type(&popped)?)));
+        let to_ret = Ok(Rc::new(Value::String(self.val_type(&popped)?.into())));
         to_ret
     }
     pub fn val_type(&mut self, popped: &Value) -> Result<String> {
-        let to_ret = Ok(match popped {
-            Value::Constructor(idx, _) => self.input.types[self.input.types.iter().position(|(_, start, end)| (start..=end).contains(&&idx)).unwrap()].0.clone(),
-            Value::Tuple(content) => format!("({})", content.iter().map(|v|{
-                let to_ret = self.val_type(v)?;
-                Ok(to_ret)
-            }).collect::<Result<Vec<String>>>()?.join(" ")),
-            Value::String(_) => "String".to_string(),
-            Value::Single(_) => "Single".to_string(),
-            Value::Integer(_) => "Integer".to_string(),
-            Value::Lambda(..) => "Lambda".to_string(),
-        });
-        to_ret
+        // From a worklist, as the types of the tuples are made of the ones of their values.
+        let mut to_ret = String::new();
+        let mut pending = vec![Part::Value(popped, false)];
+        while let Some(part) = pending.pop() {
+            match part {
+                Part::Text(text) => to_ret.push_str(text),
+                Part::Value(Value::Constructor(idx, _), _) => to_ret.push_str(&self.input.types[self.input.types.iter().position(|(_, start, end)| (start..=end).contains(&idx)).unwrap()].0),
+                Part::Value(Value::Tuple(content), _) => {
+                    to_ret.push('(');
+                    pending.push(Part::Text(")"));
+                    for (idx, value) in content.iter().enumerate().rev() {
+                        pending.push(Part::Value(value, false));
+                        if idx > 0 {
+                            pending.push(Part::Text(" "));
+                        }
+                    }
+                }
+                Part::Value(Value::String(_), _) => to_ret.push_str("String"),
+                Part::Value(Value::Single(_), _) => to_ret.push_str("Single"),
+                Part::Value(Value::Integer(_), _) => to_ret.push_str("Integer"),
+                Part::Value(Value::Lambda(..), _) => to_ret.push_str("Lambda"),
+            }
+        }
+        Ok(to_ret)
     }
     fn register_builtin(
         &mut self,
@@ -224,20 +395,97 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
         }
 
     }
-    fn decl(&mut self, sym_id: u16, val: Rc<Value>, ctx: &mut Vec<Rc<Value>>, sym_ref: &mut Vec<u16>) {
-        let id = if !sym_ref.contains(&sym_id) {
-            sym_ref.push(sym_id);
-            sym_ref.len() - 1
-        } else {
-            sym_ref.iter().position(|id| id == &sym_id).unwrap()
+    // Reads a variable from the running function.
+    fn load(&self, slot: Slot) -> Result<Rc<Value>> {
+        let frame = match self.frames.last() {
+            Some(call) => &call.frame,
+            None => bug!("NO_RUNNING_FRAME"),
+        };
+        let value = match slot {
+            Slot::Local(slot) => frame.get(slot),
+            Slot::Upvalue(idx) => frame.upvalues.get(idx as usize).map(|cell| cell.borrow().clone()),
+            Slot::Global(id) => match self.globals.get(id as usize) {
+                Some(Some(value)) => Some(value.clone()),
+                _ => return error!(=> "Unbound variable: {}.", self.input.symbols[id as usize]),
+            },
         };
-        if id == ctx.len() {
-            ctx.push(val);
-        } else {
-            ctx[id] = val;
+        match value {
+            Some(value) => Ok(value),
+            None => bug!("UNBOUND_SLOT"),
+        }
+    }
+    fn constant(&mut self, id: u16) -> Rc<Value> {
+        // Code given to `eval` adds constants after the ones already there.
+        if self.constants.len() <= id as usize {
+            let values = self.input.constants[self.constants.len()..].iter().map(|lit| Rc::new(to_val(lit))).collect::<Vec<_>>();
+            self.constants.extend(values);
+        }
+        self.constants[id as usize].clone()
+    }
+    fn define_global(&mut self, sym_id: u16, value: Rc<Value>) {
+        if self.globals.len() <= sym_id as usize {
+            self.globals.resize(sym_id as usize + 1, None);
+        }
+        self.globals[sym_id as usize] = Some(value);
+    }
+    fn frame(&mut self) -> &mut Frame {
+        match self.frames.last_mut() {
+            Some(call) => &mut call.frame,
+            None => bug!("NO_RUNNING_FRAME"),
+        }
+    }
+    // The instructions of a chunk, or the top-level ones.
+    fn code(&self, chunk: Option<u16>) -> &[OpCode] {
+        match chunk {
+            Some(chunk) => &self.input.chunks[chunk as usize].instructions,
+            None => &self.input.instructions,
+        }
+    }
+    fn jump(&mut self, offset: u16) {
+        if let Some(call) = self.frames.last_mut() {
+            call.ip += offset as usize;
+        }
+    }
+    // Counts the bytes allocated, against the memory limit.
+    fn allocate(&mut self, bytes: usize) -> Result<()> {
+        self.allocated += bytes;
+        match self.limits.memory {
+            Some(memory) if self.allocated > memory => error!(=> "Out of memory: more than {} bytes allocated.", memory),
+            _ => Ok(()),
         }
     }
-    fn eval_opcode(&mut self, opcode: OpCode, ctx: &mut Vec<Rc<Value>>, sym_ref: &mut Vec<u16>, instructions: &[OpCode]) -> Result<()> {
+    fn alloc(&mut self, value: Value) -> Result<Rc<Value>> {
+        self.allocate(size(&value))?;
+        Ok(Rc::new(value))
+    }
+    // Enters a chunk, the amount of running functions being bounded by the call depth limit.
+    // A tail call replaces the running function, whose frame is not needed anymore.
+    fn call(&mut self, chunk: u16, frame: Frame, height: Option<usize>, tail: bool) -> Result<()> {
+        if tail {
+            self.frames.pop();
+        }
+        if self.frames.len() >= self.limits.depth.unwrap_or(STACK_SIZE) {
+            return error!(=> "Stack overflow.");
+        }
+        self.allocate(std::mem::size_of::<CallFrame>() + frame.locals.len() * std::mem::size_of::<Local>() + frame.upvalues.len() * std::mem::size_of::<Cell>())?;
+        self.frames.push(CallFrame {
+            chunk: Some(chunk),
+            ip: 0,
+            frame,
+            height,
+        });
+        Ok(())
+    }
+    // Leaves the running chunk, once all its instructions are run.
+    fn ret(&mut self) {
+        if let Some(CallFrame { height: Some(height), .. }) = self.frames.pop() {
+            // Only the value of the evaluated code is kept.
+            let result = if self.stack.len() > height { self.stack.pop() } else { None };
+            self.stack.truncate(height);
+            self.stack.push(result.unwrap_or_else(|| Rc::new(Value::Tuple(vec![]))));
+        }
+    }
+    fn execute(&mut self, opcode: OpCode) -> Result<()> {
         match opcode {
             OpCode::Panic(file, line) => if let Literal::Integer(line) = self.input.constants[line as usize] {
                 if let Literal::String(file) = self.input.constants[file as usize].clone() {
@@ -246,81 +494,87 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
                     std::process::exit(1);
                 }
             }
-            OpCode::LoadConst(id) => self.stack.push(Rc::new(to_val(&self.input.constants[id as usize]))),
-            OpCode::LoadSym(id) => {
-                let local_id = if !sym_ref.contains(&id) {
-                    error!(=> "Unbound variable: {}.", self.input.symbols[id as usize])
-                } else {
-                    Ok(sym_ref.iter().position(|sid| sid == &id).unwrap())                
-                }?;
-                self.stack.push(ctx[local_id as usize].clone())
-            },
-            OpCode::Def(sym_id, instr_length) => {
-                let saved = self.ip;
-                while self.ip < saved + instr_length as usize {
-                    self.ip += 1;
-                    let instr = instructions[self.ip];
-                    self.eval_opcode(instr, ctx, sym_ref, &instructions)?;
-                }
+            OpCode::Eval => {
+                let code = self.pop()?;
+                let constructors = self.input.constructors.iter().map(|(_, sym)| self.input.symbols[*sym as usize].clone()).collect::<Vec<String>>();
+                let exprs = Parser::new(unquote(&code, &constructors, "eval", 1)?, "eval").parse()?;
+                let symbols = self.input.symbols.iter().map(|name| (name.to_string(), true)).collect();
+                let mut compiler = Compiler::new(exprs, "eval", self.input.clone(), constructors, true, String::new(), true, self.macros.clone())?.with_namespaces(self.namespaces.clone()).with_globals(self.globals.clone()).with_limits(self.limits);
+                compiler.compile_input(symbols)?;
+                let mut output = compiler.output();
+                let instructions = std::mem::replace(&mut output.instructions, std::mem::take(&mut self.input.instructions));
+                // The evaluated code is run as a function of its own, in a fresh frame.
+                output.chunks.push(Chunk {
+                    instructions: instructions.into(),
+                    arity: 0,
+                    slots: 0,
+                    captures: Rc::new([]),
+                });
+                self.input = output;
+                self.call(self.input.chunks.len() as u16 - 1, Frame::default(), Some(self.stack.len()), false)?;
+            }
+            OpCode::LoadConst(id) => {
+                let value = self.constant(id);
+                self.stack.push(value)
+            }
+            OpCode::LoadGlobal(id) => {
+                let value = self.load(Slot::Global(id))?;
+                self.stack.push(value)
+            }
+            OpCode::LoadLocal(slot) => {
+                let value = self.load(Slot::Local(slot))?;
+                self.stack.push(value)
+            }
+            OpCode::LoadUpvalue(idx) => {
+                let value = self.load(Slot::Upvalue(idx))?;
+                self.stack.push(value)
+            }
+            OpCode::Def(sym_id) => {
                 let popped = self.pop()?;
-                let id = if !sym_ref.contains(&sym_id) {
-                    sym_ref.push(sym_id);
-                    sym_ref.len() - 1
-                } else {
-                    sym_ref.iter().position(|id| id == &sym_id).unwrap()
-                };
-                let popped = if let Value::Lambda(idx, save, _) = (*popped).clone() {
-                    let to_ret = Rc::new(Value::Lambda(idx, save, (*sym_ref).clone()));
-                    if id == self.saves[save as usize].len() {
-                        self.saves[save as usize].push(to_ret.clone());
-                    } else {
-                        self.saves[save as usize][id] = to_ret.clone();
-                    }
-                    to_ret
-                } else {
-                    popped
-                };
-                if id == ctx.len() {
-                    ctx.push(popped);
-                } else {
-                    ctx[id] = popped;
-                }
+                self.define_global(sym_id, popped);
+            }
+            OpCode::DefLocal(slot) => {
+                let popped = self.pop()?;
+                self.frame().set(slot, popped);
             }
             OpCode::Lambda(chunk_id) => {
-                self.saves.push(ctx.clone());
-                self.stack.push(Rc::new(Value::Lambda(chunk_id, self.saves.len() as u16 - 1, sym_ref.clone())));
+                let frame = match self.frames.last_mut() {
+                    Some(call) => &mut call.frame,
+                    None => bug!("NO_RUNNING_FRAME"),
+                };
+                let heap = &mut self.heap;
+                let upvalues = self.input.chunks[chunk_id as usize].captures.iter().map(|capture| match capture {
+                    Capture::Local(slot) => frame.capture(*slot, heap),
+                    Capture::Upvalue(idx) => frame.upvalues[*idx as usize].clone(),
+                }).collect();
+                let lambda = self.alloc(Value::Lambda(chunk_id, upvalues))?;
+                self.stack.push(lambda);
             },
-            OpCode::Call(argc) => {
-                let mut args = vec![];
-                for _ in 0..argc {
-                    args.push(self.pop()?);
-                }
-                args.reverse();
-                let func = self.pop()?;
-                if let Value::Lambda(chunk, ctx_id, mut sym_ref) = (*func).clone() {
-                    let mut ctx = (*self.saves[ctx_id as usize]).to_vec();
-                    let chunk = self.input.chunks[chunk as usize].clone();
-                    if chunk.reference.len() != args.len() {
+            OpCode::Call(argc) | OpCode::TailCall(argc) => {
+                let base = match self.stack.len().checked_sub(argc as usize + 1) {
+                    Some(base) => base,
+                    None => return error!(=> "Stack underflow."),
+                };
+                let func = self.stack[base].clone();
+                if let Value::Lambda(chunk_id, upvalues) = &*func {
+                    let chunk = &self.input.chunks[*chunk_id as usize];
+                    if chunk.arity != argc {
                         return error!(
                             => "Expected {} arguments, found {}.",
-                            chunk.reference.len(),
-                            args.len()
+                            chunk.arity,
+                            argc
                             );
                     }
-                    for idx in 0..chunk.reference.len() {
-                        // Fetch arguments and replace the symbol table.
-                        let val = args[idx].clone();
-                        let sym_id = chunk.reference[idx];
-                        self.decl(sym_id, val, &mut ctx, &mut sym_ref);
-                    }
-                    let prev_ip = self.ip;
-                    self.ip = 0; // Reset the instruction counter to fit chunk instructions
-                    while self.ip < chunk.instructions.len() {
-                        let instr = chunk.instructions[self.ip];
-                        self.eval_opcode(instr, &mut ctx, &mut sym_ref, &chunk.instructions)?; // Eval chunk body.
-                        self.ip += 1;
-                    }
-                    self.ip = prev_ip;
+                    // The arguments are moved from the stack to the first slots, the other ones are filled by the body.
+                    let mut locals = Vec::with_capacity(chunk.slots as usize);
+                    locals.extend(self.stack.drain(base + 1..).map(Local::Value));
+                    locals.resize(chunk.slots as usize, Local::Value(self.unit.clone()));
+                    self.stack.pop();
+                    let frame = Frame {
+                        locals,
+                        upvalues: upvalues.clone(),
+                    };
+                    self.call(*chunk_id, frame, None, matches!(opcode, OpCode::TailCall(_)))?;
                 } else {
                     return error!(=> "Expected a Lambda, found a {}.", self.val_type(&*func)?);
                 }
@@ -334,190 +588,176 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
                         );
                 }
                 let to_push = f(self)?;
+                self.allocate(size(&to_push))?;
                 self.stack.push(to_push);
             }
-            OpCode::Constructor(idx, to_eval) => {
+            OpCode::Constructor(idx) => {
                 let (amount, _) = self.input.constructors[idx as usize];
-                let saved = self.ip;
-                while self.ip < saved + to_eval as usize {
-                    self.ip += 1;
-                    let instruction = instructions[self.ip].clone();
-                    self.eval_opcode(instruction, ctx, sym_ref, instructions.clone())?;
-                }
                 let mut vals = (0..amount)
                     .map(|_| self.pop())
                     .collect::<Result<Vec<Rc<Value>>>>()?;
                 vals.reverse();
-                self.stack.push(Rc::new(Value::Constructor(idx, vals)));
+                let constructor = self.alloc(Value::Constructor(idx, vals))?;
+                self.stack.push(constructor);
             }
-            OpCode::Tuple(to_eval, valc) => {
-                let saved = self.ip;
-                while self.ip < saved + to_eval as usize {
-                    self.ip += 1;
-                    let instr = instructions[self.ip];
-                    self.eval_opcode(instr, ctx, sym_ref, instructions)?;
-                };
+            OpCode::Tuple(valc) => {
                 let mut vals = (0..valc)
                     .map(|_| self.pop())
                     .collect::<Result<Vec<Rc<Value>>>>()?;
                 vals.reverse();
-                self.stack.push(Rc::new(Value::Tuple(vals)));
+                let tuple = self.alloc(Value::Tuple(vals))?;
+                self.stack.push(tuple);
             }
             OpCode::Match(idx) => {
                 let to_match = self.pop()?;
-                let patterns = self.input.matches[idx as usize].clone();
-                let plausible = patterns.into_iter().map(|(pat, to_exec)| {
-                    if self.is_plausible(pat, &to_match) {
-                        Some((pat, to_exec))
-                    } else {
-                        None
-                    }
-                }).filter(|p| !p.is_none()).map(|p| p.unwrap()).collect::<Vec<(u16, Vec<OpCode>)>>();
-                for plausible in plausible.into_iter() {
-                    match self.match_and_bound(&to_match, plausible.0) {
-                        Some(to_bind) => {
-                            let mut new_ctx = ctx.clone();
-                            let mut new_ref = sym_ref.clone();
-                            let mut new_stack = (0..to_bind.len()).map(|_| self.pop()).rev().collect::<Result<Vec<_>>>()?;
-                            to_bind.into_iter().for_each(|sym_id| {
-                                let val = new_stack.pop().unwrap();
-                                self.decl(sym_id, val, &mut new_ctx, &mut new_ref);    
-                            });
-                            let saved = self.ip;
-                            self.ip = 0;
-                            while self.ip < plausible.1.len() {
-                                let instr = plausible.1[self.ip];
-                                self.eval_opcode(instr, &mut new_ctx, &mut new_ref, &plausible.1)?;
-                                self.ip += 1;
+                let r#match = self.input.matches[idx as usize].clone();
+                let mut occurrences = vec![to_match.clone(); r#match.occurrences as usize];
+                let mut node = &r#match.tree;
+                let (arm, bindings) = loop {
+                    match node {
+                        Decision::Fail => return error!(=> "No pattern to be matched."),
+                        Decision::Leaf(arm, bindings) => break (*arm, bindings),
+                        Decision::Switch(occurrence, cases, default) => {
+                            let val = occurrences[*occurrence as usize].clone();
+                            node = default;
+                            for (test, child, subtree) in cases {
+                                if self.test(test, &val, &mut occurrences, *child as usize)? {
+                                    node = subtree;
+                                    break;
+                                }
                             }
-                            self.ip = saved;
-                            return Ok(());
-                        },
-                        None => {},
+                        }
                     }
-                }
-                return error!(=> "No pattern to be matched.");
+                };
+                bindings.iter().for_each(|(slot, occurrence)| {
+                    self.frame().set(*slot, occurrences[*occurrence as usize].clone());
+                });
+                self.jump(r#match.arms[arm as usize]);
             }
+            OpCode::Jump(offset) => self.jump(offset),
         }
 
         Ok(())
     }
-    fn match_and_bound(&mut self, val: &Rc<Value>, pat_idx: u16) -> Option<Vec<u16>> {
-        let pat = &self.input.patterns[pat_idx as usize];
-        match pat {
-            BytecodePattern::Any => Some(vec![]),
-            BytecodePattern::Var(idx) => {
-                self.stack.push((*val).clone());
-                Some(vec![*idx])
-            }
-            BytecodePattern::Literal(idx) => {
-                let lit = &self.input.constants[*idx as usize];
-                match (**val).clone() {
-                    Value::Integer(lhs) => match lit {
-                        Literal::Integer(rhs) => if lhs == *rhs { Some(vec![]) } else { None },
-                        _ => None,
-                    }
-                    Value::Single(lhs) => match lit {
-                        Literal::Single(rhs) => if lhs == *rhs { Some(vec![]) } else { None },
-                        _ => None,
-                    }
-                    Value::String(lhs) => match lit {
-                        Literal::String(rhs) => if lhs == *rhs { Some(vec![]) } else { None },
-                        _ => None
-                    }
-                    _ => bug!("FAILED_PLAUSIBLE_UNEXPECTED_VALUE")
+    // Runs a decision tree test against a value, and stores the values of its sub-occurrences from `child` if it passes.
+    fn test(&mut self, test: &Test, val: &Rc<Value>, occurrences: &mut [Rc<Value>], child: usize) -> Result<bool> {
+        Ok(match test {
+            Test::Constr(idx) => match &**val {
+                Value::Constructor(to_match_idx, vals) if to_match_idx == idx => {
+                    occurrences[child..child + vals.len()].clone_from_slice(vals);
+                    true
                 }
+                _ => false,
             }
-            BytecodePattern::Tuple(pats) => if let Value::Tuple(vals) = (**val).clone() {
-                let pats = pats.clone();
-                if vals.len() == pats.len() {
-                    let mut to_ret = vec![];
-                    for i in 0..vals.len() {
-                        if !self.is_plausible(pats[i], &vals[i]) {
-                            return None;
-                        } else {
-                            match self.match_and_bound(&vals[i], pats[i]) {
-                                Some(ctx) => to_ret.extend(ctx),
-                                None => return None,
-                            }
-                        }
-                    }
-                    Some(to_ret)
-                } else {
-                    None
+            Test::Tuple(length) => match &**val {
+                Value::Tuple(vals) if vals.len() == *length as usize => {
+                    occurrences[child..child + vals.len()].clone_from_slice(vals);
+                    true
                 }
-            } else {
-                None
+                _ => false,
             }
-            BytecodePattern::Constr(idx, pats) => if let Value::Constructor(to_match_idx, vals) = (**val).clone() {
-                if to_match_idx == *idx {
-                    let pats = pats.clone();
-                    let mut to_ret = vec![];
-                    for i in 0..vals.len() {
-                        if !self.is_plausible(pats[i], &vals[i]) {
-                            return None;
-                        } else {
-                            match self.match_and_bound(&vals[i], pats[i]) {
-                                Some(ctx) => to_ret.extend(ctx),
-                                None => return None,
-                            }
-                        }
+            Test::Literal(idx) => match (&**val, &self.input.constants[*idx as usize]) {
+                (Value::Integer(lhs), Literal::Integer(rhs)) => lhs == rhs,
+                (Value::Single(lhs), Literal::Single(rhs)) => lhs == rhs,
+                (Value::String(lhs), Literal::String(rhs)) => **lhs == **rhs,
+                _ => false,
+            }
+            Test::Range(start, end) => match (&**val, &self.input.constants[*start as usize], &self.input.constants[*end as usize]) {
+                (Value::Integer(i), Literal::Integer(start), Literal::Integer(end)) => (start..=end).contains(&i),
+                (Value::Single(s), Literal::Single(start), Literal::Single(end)) => (start..=end).contains(&s),
+                _ => false,
+            }
+            Test::Prefix(prefix) => match (&**val, &self.input.constants[*prefix as usize]) {
+                (Value::String(s), Literal::String(prefix)) => match s.strip_prefix(prefix.as_str()) {
+                    Some(rest) => {
+                        occurrences[child] = Rc::new(Value::String(rest.into()));
+                        true
                     }
-                    Some(to_ret)
-                } else {
-                    None
+                    None => false,
                 }
-            } else {
-                None
-            },
-        }
+                _ => false,
+            }
+            Test::Pin(slot) => {
+                let pinned = self.load(*slot)?;
+                matches!(self._cmp(val, &pinned), Ok(std::cmp::Ordering::Equal))
+            }
+        })
     }
-    fn is_plausible(&self, pat: u16, to_match: &Value) -> bool {
-        let pat = self.input.patterns[pat as usize].clone();
-        match pat {
-            BytecodePattern::Var(_) | BytecodePattern::Any => true,
-            BytecodePattern::Constr(_, _) => if let Value::Constructor(_, _) = to_match {
-                true
-            } else {
-                false
+    // Stops the program once it has used up the resources it is given.
+    fn check_limits(&mut self) -> Result<()> {
+        let stack = self.limits.stack.unwrap_or(STACK_SIZE);
+        if self.stack.len() > stack {
+            return error!(=> "Operand stack overflow: more than {} values.", stack);
+        }
+        self.steps += 1;
+        if let Some(fuel) = self.limits.fuel {
+            if self.steps > fuel {
+                return error!(=> "Out of fuel: more than {} instructions run.", fuel);
             }
-            BytecodePattern::Tuple(_) => if let Value::Tuple(_) = to_match {
-                true
-            } else {
-                false
+        }
+        // Reading the clock is slower than running most instructions.
+        if let (Some(deadline), 0) = (self.deadline, self.steps % 1024) {
+            if Instant::now() > deadline {
+                return error!(=> "Timeout: run for more than {}ms.", self.limits.timeout.unwrap_or_default().as_millis());
             }
-            BytecodePattern::Literal(lid) => match &self.input.constants[lid as usize] {
-                Literal::Integer(_) => match to_match {
-                    Value::Integer(_) => true,
-                    _ => false,
-                }
-                Literal::Single(_) => match to_match {
-                    Value::Single(_) => true,
-                    _ => false
-                }
-                Literal::String(_) => match to_match {
-                    Value::String(_) => true,
-                    _ => false,
+        }
+        Ok(())
+    }
+    // Frees the cycles of cells that the running code cannot reach anymore.
+    pub fn collect(&mut self) -> Stats {
+        let mut values = self.stack.clone();
+        let mut cells = vec![];
+        for call in &self.frames {
+            for local in &call.frame.locals {
+                match local {
+                    Local::Value(value) => values.push(value.clone()),
+                    Local::Cell(cell) => cells.push(cell.clone()),
                 }
             }
+            cells.extend(call.frame.upvalues.iter().cloned());
         }
+        values.extend(self.globals.iter().flatten().cloned());
+        self.heap.collect(values, cells)
     }
-    pub fn eval(&mut self, mut sym_ref: Vec<u16>, mut ctx: Vec<Rc<Value>>, mut step: bool) -> Result<(Vec<Rc<Value>>, Vec<u16>, Vec<Vec<Rc<Value>>>)> {
+    // Runs the top-level instructions with the given global definitions, and returns them.
+    pub fn eval(&mut self, globals: Globals, mut step: bool) -> Result<Globals> {
         if step {
             println!("Welcome to the Orion DeBugger, type `h' to get help.");
         }
-        while self.ip < self.input.instructions.len() {
-            let instruction = self.input.instructions[self.ip];
-            let instrs = self.input.instructions.clone();
-            self.eval_opcode(instruction, &mut ctx, &mut sym_ref, &instrs)?;
-            if step {
-                step = self.dbg_step();
+        self.globals = globals;
+        self.steps = 0;
+        self.allocated = 0;
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.frames = vec![CallFrame {
+            chunk: None,
+            ip: 0,
+            frame: Frame::default(),
+            height: None,
+        }];
+        while let Some(&CallFrame { chunk, ip, .. }) = self.frames.last() {
+            match self.code(chunk).get(ip).copied() {
+                Some(instruction) => {
+                    self.jump(1);
+                    self.execute(instruction)?;
+                    self.check_limits()?;
+                    if self.heap.is_full() {
+                        self.collect();
+                    }
+                    if step {
+                        step = self.dbg_step();
+                    }
+                }
+                None => self.ret(),
             }
-            self.ip += 1;
         }
-        Ok((ctx, sym_ref, self.saves.clone()))
+        Ok(self.globals.clone())
     }
     pub fn dbg_step(&mut self) -> bool {
+        // The instructions of the running function.
+        let (instructions, ip) = match self.frames.last() {
+            Some(call) => (self.code(call.chunk).to_vec(), call.ip),
+            None => (vec![], 0),
+        };
         loop {
             print!("odb> ");
             io::stdout().flush().unwrap();
@@ -534,21 +774,24 @@ i\tDisplay the 15 instructions around the instruction pointer.";
                 "h" => println!("{}", help),
                 "n" => return true,
                 "q" => return false,
-                "c" => println!("{}", self.input.instructions[self.ip]),
+                "c" => match instructions.get(ip) {
+                    Some(instruction) => println!("{}", instruction),
+                    None => println!("RET"),
+                },
                 "s" => println!("[{}]", self.stack.iter().skip(1).fold(self.stack.iter().nth(0).and_then(|e| Some(self.display_value(e.clone(), true))).unwrap_or("".to_string()), |acc, x| format!("{}, {}", acc, self.display_value(x.clone(), true)))),
                 "i" => {
-                    let start = if 7 > self.ip {
-                        (0, -(self.ip as i32))
+                    let start = if 7 > ip {
+                        (0, -(ip as i32))
                     } else {
-                        (self.ip - 7, -7)
+                        (ip - 7, -7)
                     };
-                    let end = if self.ip + 7  > self.input.instructions.len() {
-                        (self.input.instructions.len(), self.input.instructions.len() as i32 - self.ip as i32)
+                    let end = if ip + 7  > instructions.len() {
+                        (instructions.len(), instructions.len() as i32 - ip as i32)
                     } else {
-                        (self.ip + 7, 7 as i32)
+                        (ip + 7, 7 as i32)
                     };
                     let indices = (start.1..end.1).collect::<Vec<i32>>();
-                    self.input.instructions[start.0..end.0].iter().enumerate().for_each(|(idx, i)| {
+                    instructions[start.0..end.0].iter().enumerate().for_each(|(idx, i)| {
                         println!("{}{}    {}", if indices[idx] > -1 { " " } else { "" }, indices[idx], i);
                     })
                 }
@@ -567,6 +810,361 @@ mod test {
     use crate::compiler::Compiler;
     use std::time::Instant;
 
+    fn lib() -> String {
+        format!("{}/lib", env!("CARGO_MANIFEST_DIR"))
+    }
+    fn run(code: &str) -> Result<String> {
+        run_in::<256>(code)
+    }
+    fn run_in<const STACK_SIZE: usize>(code: &str) -> Result<String> {
+        run_limited::<STACK_SIZE>(code, Limits::default())
+    }
+    fn run_limited<const STACK_SIZE: usize>(code: &str, limits: Limits) -> Result<String> {
+        run_with::<STACK_SIZE>(code, limits, false)
+    }
+    fn run_with<const STACK_SIZE: usize>(code: &str, limits: Limits, optimize: bool) -> Result<String> {
+        let tokens = Lexer::new(code, "TEST").proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let mut compiler = Compiler::new(ast, "TEST", Bytecode::new(), vec![], false, lib(), true, vec![])?.with_limits(limits).with_optimizations(optimize);
+        let (bytecode, _, _, macros) = compiler.compile(vec![])?;
+        let mut vm = VM::<STACK_SIZE>::new(bytecode).with_limits(limits);
+        vm.macros = macros;
+        vm.namespaces = compiler.namespaces();
+        vm.eval(vec![], false)?;
+        let top = vm.pop()?;
+        Ok(vm.display_value(top, true))
+    }
+
+    #[test]
+    fn quotation() -> Result<()> {
+        assert_eq!(run("'(f x 5)")?, run("(Cons (Symbol \"f\") (Cons (Symbol \"x\") (Cons 5 Nil)))")?);
+        assert_eq!(run("(def x 3) (def xs [4 5]) `(f ~x ~@xs 6)")?, run("[(Symbol \"f\") 3 4 5 6]")?);
+        assert_eq!(run("''a")?, run("[(Symbol \"quote\") (Symbol \"a\")]")?);
+        // Splicing does not depend on the `append` in scope.
+        assert_eq!(run("(def xs [2 3]) ((λ (append) `(1 ~@xs 4)) 0)")?, run("[1 2 3 4]")?);
+        assert_eq!(run("(def append (λ (x y) x)) (def xs [2 3]) `(1 ~@xs 4)")?, run("[1 2 3 4]")?);
+        assert!(run("`(1 ~@5)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn eval() -> Result<()> {
+        assert_eq!(run("(eval '(+ 1 2))")?, "3");
+        assert_eq!(run("(def n 4) (eval `(* ~n ~n))")?, "16");
+        assert_eq!(run("(eval '(if (= 1 1) \"yes\" \"no\"))")?, "\"yes\"");
+        assert_eq!(run("(def double (λ (x) (* x 2))) (eval '(double 21))")?, "42");
+        assert_eq!(run("(eval '(def y 5)) (eval 'y)")?, "5");
+        assert!(run("(def f (λ () (eval '1)))").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn forward_references() -> Result<()> {
+        assert_eq!(run("(def even? (λ (n) (if (= n 0) #t (odd? (- n 1)))))
+        (def odd? (λ (n) (if (= n 0) #f (even? (- n 1)))))
+        (, (even? 10) (odd? 7) (even? 3))")?, "(True True False)");
+        assert_eq!(run("(def area (λ (s) (match s ((Square c) (square c))))) (def square (λ (x) (* x x))) (enum Shape (Square c)) (area (Square 4))")?, "16");
+        // The definitions of a file shadow the loaded ones, even before them.
+        assert_eq!(run("(def f (λ () (length 1))) (def length (λ (x) 0)) (f)")?, "0");
+        assert_eq!(run("(def x y) (def y 1) x").err().map(|e| e.2), Some("Unbound variable: y.".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn shadowing() -> Result<()> {
+        assert_eq!(run("(def f (λ (x) (λ (x) (+ x 1)))) ((f 1) 5)")?, "6");
+        assert_eq!(run("((λ (x) (, ((λ (x) x) 2) x)) 1)")?, "(2 1)");
+        assert_eq!(run("(def x 10) (def f (λ (x) (match x ((Just x) x) (Nothing 0)))) (, (f (Just 3)) x)")?, "(3 10)");
+        assert_eq!(run("(def g (λ (n) (begin (def n (* n 2)) (def m (λ () n)) (m)))) (g 4)")?, "8");
+        Ok(())
+    }
+
+    #[test]
+    fn closures() -> Result<()> {
+        assert_eq!(run("(def adder (λ (n) (λ (m) (+ n m)))) (def add2 (adder 2)) (, (add2 3) ((adder 5) 1))")?, "(5 6)");
+        assert_eq!(run("((((λ (a) (λ (b) (λ (c) (, a b c)))) 1) 2) 3)")?, "(1 2 3)");
+        assert_eq!(run("(begin (def count (λ (n) (if (= n 0) 0 (+ 1 (count (- n 1)))))) (count 5))")?, "5");
+        assert_eq!(run("(match (Just 4) ((Just v) ((λ () (* v v)))) (_ 0))")?, "16");
+        Ok(())
+    }
+
+    #[test]
+    fn deep_recursion() -> Result<()> {
+        let count = "(def count (λ (n) (match n (0 0) (_ (+ 1 (count (- n 1)))))))";
+        assert_eq!(run_in::<400000>(&format!("{} (count 100000)", count))?, "100000");
+        assert_eq!(run_limited::<256>(&format!("{} (count 100000)", count), Limits { stack: Some(400000), ..Limits::default() }).err().map(|e| e.2), Some("Stack overflow.".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn limits() -> Result<()> {
+        let error = |code: &str, limits: Limits| run_limited::<256>(code, limits).err().map(|e| e.2);
+        let count = "(def count (λ (n) (match n (0 0) (_ (+ 1 (count (- n 1)))))))";
+        let forever = "(def loop (λ (n) (loop (+ n 1)))) (loop 0)";
+        assert_eq!(error(&format!("{} (count 1000)", count), Limits { stack: Some(100), ..Limits::default() }), Some("Operand stack overflow: more than 100 values.".to_string()));
+        assert_eq!(error(&format!("{} (count 1000)", count), Limits { stack: Some(10000), depth: Some(100), ..Limits::default() }), Some("Stack overflow.".to_string()));
+        assert_eq!(error(forever, Limits { fuel: Some(10000), ..Limits::default() }), Some("Out of fuel: more than 10000 instructions run.".to_string()));
+        assert_eq!(error(forever, Limits { timeout: Some(Duration::from_millis(50)), ..Limits::default() }), Some("Timeout: run for more than 50ms.".to_string()));
+        assert_eq!(error("(fill 100000 0)", Limits { memory: Some(100000), ..Limits::default() }), Some("Out of memory: more than 100000 bytes allocated.".to_string()));
+        assert_eq!(run_limited::<256>(&format!("{} (count 100)", count), Limits { fuel: Some(100000), timeout: Some(Duration::from_secs(10)), memory: Some(1000000), ..Limits::default() })?, "100");
+        // The code run by procedural macros is bounded too.
+        assert_eq!(error(&format!("{} (macro m () (begin (count 1000) 1)) (m)", count), Limits { fuel: Some(1000), ..Limits::default() }).map(|e| e.contains("Out of fuel")), Some(true));
+        Ok(())
+    }
+
+    #[test]
+    fn cycles() -> Result<()> {
+        // Each call leaves a recursive local function, whose cell holds the closure capturing it.
+        let code = "(def make (λ (n) (begin (def f (λ (x) (match x (0 n) (_ (f (- x 1)))))) (f 1))))
+        (def loop (λ (i) (match i (0 0) (_ (match (make i) (_ (loop (- i 1))))))))
+        (loop 10000)";
+        let tokens = Lexer::new(code, "TEST").proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let (bytecode, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], false, lib(), true, vec![])?.compile(vec![])?;
+        let mut vm = VM::<256>::new(bytecode);
+        vm.eval(vec![], false)?;
+        assert!(vm.heap.collected > 0);
+        let stats = vm.collect();
+        assert_eq!(stats.environments, 0);
+        assert_eq!(vm.heap.collected, 20000);
+        // The closures still in use are kept.
+        assert_eq!(run_in::<100000>("(def keep (map (range 0 3000) (λ (i) (begin (def g (λ (x) (match x (0 i) (_ (g (- x 1)))))) g))))
+        (foldl 0 + (map keep (λ (g) (g 2))))")?, "4498500");
+        Ok(())
+    }
+
+    #[test]
+    fn optimizations() -> Result<()> {
+        let programs = [
+            "(, (+ 1 2) (- 10 (* 3 4)) (/ 7 2) (% 7 3) (+ 1.5 2.) (neg 4) (< 1 2) (>= 3 2) (<= 1 2))",
+            "(, (= 1 1) (/= \"a\" \"b\") (not True) (and True False) (or False True) (cmp 3 2) (if (< 1 2) \"yes\" \"no\"))",
+            "(, (cond ((= 1 2) 0) ((= 2 2) 1)) (when True 1) (unless True 1))",
+            "(, (length (range 0 10)) (foldl 0 + (map (range 0 10) (λ (x) (* x x)))) (append [1 2] [3]) (push [1] 2) (car [1 2]) (cdr [1 2]) (empty? []) (fill 3 0))",
+            "(, (>> (Just 2) (λ (x) (Just (+ x 1)))) (>> Nothing (λ (x) x)) (match (Just (+ 1 2)) ((Just 3) \"three\") (_ \"other\")))",
+            "(, (show 42) (chars \"abc\") (strlen \"hello\") (-> 3 (+ 1) (* 2)) (format \"{}-{}\" (, 1 2)))",
+            "(def f (λ (n) (begin (def m (- n 1)) (def g (λ (x) (match x (0 m) (_ (g (- x 1)))))) (if (= n 0) 0 (+ (g 2) (f m)))))) (f 10)",
+            "(def x 3) (match (, x \"a\") ((, 3 \"a\") 1) ((, ^x _) 2) (_ 3))",
+            // Failures are left to run time.
+            "(+ 1 \"a\")",
+            "(def ack (λ (m n) (match (, m n) ((, 0 _) (+ n 1)) ((, _ 0) (ack (- m 1) 1)) (_ (ack (- m 1) (ack m (- n 1))))))) (ack 2 3)",
+        ];
+        for program in programs {
+            assert_eq!(run_with::<256>(program, Limits::default(), true).map_err(|e| e.2), run(program).map_err(|e| e.2), "{}", program);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tree_shaking() -> Result<()> {
+        let run_shaken = |code: &str, shake: bool| -> Result<(String, Bytecode)> {
+            let ast = Parser::new(Lexer::new(code, "TEST").proc_tokens()?, "TEST").parse()?;
+            let mut compiler = Compiler::new(ast, "TEST", Bytecode::new(), vec![], false, lib(), false, vec![])?.with_tree_shaking(shake);
+            let (bytecode, _, _, macros) = compiler.compile(vec![])?;
+            let mut vm = VM::<256>::new(bytecode.clone());
+            vm.macros = macros;
+            vm.namespaces = compiler.namespaces();
+            vm.eval(vec![], false)?;
+            let top = vm.pop()?;
+            Ok((vm.display_value(top, true), bytecode))
+        };
+        let programs = [
+            "(enum Shape (Square c) (Rect w h)) (def area (λ (s) (match s ((Square c) (* c c)) ((Rect w h) (* w h))))) (, (area (Rect 2 3)) (map [1 2] (λ (x) (+ x 1))))",
+            "(def x 3) (match (, x \"a\") ((, 3 \"a\") 1) ((, ^x _) 2) (_ 3))",
+            "(def n (+ 1 2)) (def m n) (, m (show (Just m)))",
+        ];
+        for program in programs {
+            let (shaken, small) = run_shaken(program, true)?;
+            let (whole, big) = run_shaken(program, false)?;
+            assert_eq!(shaken, whole, "{}", program);
+            assert!(small.chunks.len() < big.chunks.len() && small.constants.len() < big.constants.len() && small.types.len() < big.types.len(), "{}", program);
+            assert!(small.instructions.len() < big.instructions.len(), "{}", program);
+        }
+        // Unused definitions are dropped, with the code only they use.
+        let (_, bytecode) = run_shaken("(def unused (λ (x) (match x (\"a\" 1) (_ 2)))) (def main (λ () 0))", true)?;
+        assert_eq!((bytecode.chunks.len(), bytecode.constants.len(), bytecode.matches.len(), bytecode.types.len()), (1, 1, 0, 0));
+        // The code given to `eval` may use any definition.
+        let program = "(def square (λ (x) (* x x))) (def 'impure main (λ () (eval '(square 4))))";
+        let (result, bytecode) = run_shaken(program, true)?;
+        assert_eq!((result.as_str(), bytecode.chunks.len()), ("16", run_shaken(program, false)?.1.chunks.len()));
+        Ok(())
+    }
+
+    #[test]
+    fn long_lists() -> Result<()> {
+        assert_eq!(run("(begin (def l (range 0 300000)) 1)")?, "1");
+        assert_eq!(run("(= (range 0 300000) (range 0 300000))")?, "True");
+        assert_eq!(run("(cmp (, (range 0 300000) 1) (, (range 0 300000) 2))")?, "Less");
+        let list = run("(range 0 300000)")?;
+        assert!(list.starts_with("(Cons 0 (Cons 1 (Cons 2 ") && list.ends_with(&format!("(Cons 299999 Nil{}", ")".repeat(300000))));
+        assert!(run("(begin (def l (range 0 300000)) (+ 1 \"a\"))").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tail_calls() -> Result<()> {
+        assert_eq!(run("(def loop (λ (n acc) (match n (0 acc) (_ (loop (- n 1) (+ acc 1)))))) (loop 100000 0)")?, "100000");
+        assert_eq!(run("(def f (λ (n) (begin (def m (- n 1)) (if (= m 0) \"done\" (f m))))) (f 100000)")?, "\"done\"");
+        assert_eq!(run("(, (length (range 0 100000)) (length (fill 100000 0)) (foldl 0 + (range 0 1000)))")?, "(100000 100001 499500)");
+        assert_eq!(run("(strlen \"hello\")")?, "5");
+        Ok(())
+    }
+
+    #[test]
+    fn or_pattern() -> Result<()> {
+        let code = "(def short? (λ (l) (match l ((| Nil (Cons _ Nil)) True) (_ False))))";
+        assert_eq!(run(&format!("{} (short? [1])", code))?, "True");
+        assert_eq!(run(&format!("{} (short? [])", code))?, "True");
+        assert_eq!(run(&format!("{} (short? [1 2])", code))?, "False");
+        assert_eq!(run("(match (, 1 4) ((| (, 0 x) (, 1 x)) x) (_ 0))")?, "4");
+        Ok(())
+    }
+
+    #[test]
+    fn hygienic_macros() -> Result<()> {
+        assert_eq!(run("(macro or2 (a b) ((λ (t) (if t t b)) a)) (def t True) (or2 False t)")?, "True");
+        assert_eq!(run("(macro swap (a b) (match (, a b) ((, x y) (, y x)))) (def x 1) (def y 2) (swap x y)")?, "(2 1)");
+        assert_eq!(run("(macro let1 (name value body) ((λ (name) body) value)) (let1 z 5 (+ z 1))")?, "6");
+        // The definitions of a macro body do not capture the caller's variables, unlike the ones named by its arguments.
+        let tmp = "(macro m (x) (begin (def t 1) (+ t x)))";
+        assert_eq!(run(&format!("{} (def t 10) (m t)", tmp))?, "11");
+        assert_eq!(run(&format!("{} ((λ (t) (m t)) 10)", tmp))?, "11");
+        assert_eq!(run("(macro defconst (name value) (def name value)) (defconst k 3) k")?, "3");
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_macros() -> Result<()> {
+        assert_eq!(run("(and True True False)")?, "False");
+        assert_eq!(run("(and False (panic \"Not short-circuiting.\"))")?, "False");
+        assert_eq!(run("(or False False True)")?, "True");
+        assert_eq!(run("(cond ((= 1 2) 0) ((= 1 1) 1) (True 2))")?, "1");
+        assert_eq!(run("(-> 5 (+ 1) (* 2) (- 3))")?, "9");
+        assert_eq!(run("(-> 5 (+ 1) (* 2) show)")?, "\"12\"");
+        assert_eq!(run("(macro count (() 0) ((_ & rest) (+ 1 (count rest)))) (count a b c)")?, "3");
+        Ok(())
+    }
+
+    #[test]
+    fn procedural_macros() -> Result<()> {
+        assert_eq!(run("(defmacro infix (a op b) [op a b]) (infix 1 + 2)")?, "3");
+        assert_eq!(run("(def twice (λ (code) [(Symbol \"+\") code code])) (defmacro double (x) (twice x)) (double (* 3 4))")?, "24");
+        assert_eq!(run("(defmacro tuple (& items) (Cons (Symbol \",\") items)) (tuple 1 \"a\" 2.5)")?, "(1 \"a\" 2.5)");
+        // Quoting and reading back the code gives the same code.
+        let id = "(defmacro id (x) x)";
+        assert_eq!(run(&format!("{} (id (match [1 2] ((| (Cons 0 _) Nil) 0) ((@ l [a & b]) (, a l)) (_ 3)))", id))?, "(1 (Cons 1 (Cons 2 Nil)))");
+        assert_eq!(run(&format!("{} (id ((λ (x y) {{ (def z (+ x y)) (* z 2) }}) 1 2))", id))?, "6");
+        assert_eq!(run(&format!("{} (id (match \"abc\" ((++ \"a\" r) r) ((.. 0 9) \"\") (_ \"\")))", id))?, "\"bc\"");
+        // The macros of a line of the REPL use the definitions of the previous ones.
+        let ast = Parser::new(Lexer::new("(def twice (λ (code) [(Symbol \"+\") code code]))", "TEST").proc_tokens()?, "TEST").parse()?;
+        let mut compiler = Compiler::new(ast, "TEST", Bytecode::new(), vec![], false, lib(), true, vec![])?;
+        let (bytecode, symbols, constructors, macros) = compiler.compile(vec![])?;
+        let globals = VM::<256>::new(bytecode.clone()).eval(vec![], false)?;
+        let ast = Parser::new(Lexer::new("(defmacro double (x) (twice x)) (, (double (* 3 4)) (double 1))", "TEST").proc_tokens()?, "TEST").parse()?;
+        let (bytecode, ..) = Compiler::new(ast, "TEST", bytecode, constructors, true, lib(), true, macros)?.with_namespaces(compiler.namespaces()).with_globals(globals.clone()).compile(symbols)?;
+        let mut vm = VM::<256>::new(bytecode);
+        vm.eval(globals, false)?;
+        let top = vm.pop()?;
+        assert_eq!(vm.display_value(top, true), "(24 2)");
+        Ok(())
+    }
+
+    #[test]
+    fn std_modules() -> Result<()> {
+        assert_eq!(run("(import list :as l) (l.length (l.map [1 2 3] (λ (x) (* x 2))))")?, "3");
+        assert_eq!(run("(list.append [1] [2])")?, "(Cons 1 (Cons 2 Nil))");
+        assert_eq!(run("(eval '(length (map [1 2] show)))")?, "2");
+        assert!(run("(_chars \"ab\" 0)").is_err());
+        assert!(run("(string._chars \"ab\" 0)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn format() -> Result<()> {
+        assert_eq!(run("(format \"{1} {0} {}\" (, \"a\" \"b\"))")?, "\"b a a\"");
+        assert_eq!(run("(format \"{name} is {age}\" (, (, \"name\" \"Bob\") (, \"age\" 42)))")?, "\"Bob is 42\"");
+        assert_eq!(run("(format \"[{:>5}|{:<4}|{:*^7}|{:5}]\" (, 42 \"ab\" \"mid\" 7))")?, "\"[   42|ab  |**mid**|    7]\"");
+        assert_eq!(run("(format \"{:.3} {:.2} {:x} {:X} {:08b}\" (, 3.14159 \"abc\" 255 255 5))")?, "\"3.142 ab ff FF 00000101\"");
+        assert_eq!(run("(format \"{:05}\" (, (neg 42)))")?, "\"-0042\"");
+        assert_eq!(run("(format \"{{}} {{{}}}\" (, 1))")?, "\"{} {1}\"");
+        assert!(run("(format \"{} {}\" (, 1))").is_err());
+        assert!(run("(format \"{}\" (, 1 2))").is_err());
+        assert!(run("(format \"{missing}\" (, 1))").is_err());
+        assert!(run("(format \"{:x}\" (, 1.5))").is_err());
+        assert!(run("(format \"{\" (,))").is_err());
+        assert!(run("(format \"}\" (,))").is_err());
+        // Widths and precisions are bounded, even when they overflow.
+        assert_eq!(run("(format \"{:.65535}\" (, \"ab\"))")?, "\"ab\"");
+        assert!(run("(format \"{:65535.65535}\" (, 1.5))").is_ok());
+        assert!(run("(format \"{:.99999}\" (, 1.5))").is_err());
+        assert!(run("(format \"{:99999999999}\" (, 1))").is_err());
+        assert!(run("(format \"{:.99999999999999999999999}\" (, 1.5))").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn interpolation() -> Result<()> {
+        assert_eq!(run("(def x 3) \"x = ${x}, y = ${(+ x 1)}\"")?, "\"x = 3, y = 4\"");
+        assert_eq!(run("(def name \"Bob\") \"{} ${name} {}\"")?, "\"{} Bob {}\"");
+        assert_eq!(run("\"${[1 2]}\"")?, "\"(Cons 1 (Cons 2 Nil))\"");
+        assert_eq!(run("(def s \"ü\") \"é ${s} à\"")?, "\"é ü à\"");
+        Ok(())
+    }
+
+    #[test]
+    fn reader_macros() -> Result<()> {
+        let pairs = "(macro pairs (() Nil) ((k v & rest) (Cons (, k v) (pairs rest)))) (reader #{} pairs)";
+        assert_eq!(run(&format!("{} #{{1 \"one\" 2 \"two\"}}", pairs))?, "(Cons (1 \"one\") (Cons (2 \"two\") Nil))");
+        assert_eq!(run("(defmacro sym (s) (Symbol s)) (reader #\"\" sym) (def x 5) #\"x\"")?, "5");
+        // Reader macros survive a round trip through quoted code.
+        assert_eq!(run(&format!("{} (defmacro id (x) x) (id #{{1 2}})", pairs))?, "(Cons (1 2) Nil)");
+        assert!(run("#[1 2]").is_err());
+        assert!(run("(reader #[] undefined)").is_err());
+        // `reader` is still a valid name.
+        assert_eq!(run("(def reader (λ (x) (+ x 1))) (reader 2)")?, "3");
+        assert_eq!(run("((λ (reader) reader) 4)")?, "4");
+        Ok(())
+    }
+
+    #[test]
+    fn as_pattern() -> Result<()> {
+        assert_eq!(run("(match (Just 3) ((@ whole (Just x)) (, whole x)) (_ ()))")?, "((Just 3) 3)");
+        Ok(())
+    }
+
+    #[test]
+    fn range_pattern() -> Result<()> {
+        let code = "(def digit? (λ (n) (match n ((.. 0 9) True) (_ False))))";
+        assert_eq!(run(&format!("{} (, (digit? 0) (digit? 9) (digit? 10))", code))?, "(True True False)");
+        assert_eq!(run("(match 0.5 ((.. 0. 1.) 1) (_ 0))")?, "1");
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_pattern() -> Result<()> {
+        let code = "(def path (λ (url) (match url ((++ \"http://\" rest) rest) (_ \"\"))))";
+        assert_eq!(run(&format!("{} (path \"http://orion.org\")", code))?, "\"orion.org\"");
+        assert_eq!(run(&format!("{} (path \"ftp://orion.org\")", code))?, "\"\"");
+        Ok(())
+    }
+
+    #[test]
+    fn pin_pattern() -> Result<()> {
+        let code = "(def same? (λ (x y) (match y ((^ x) True) (_ False))))";
+        assert_eq!(run(&format!("{} (, (same? 3 3) (same? 3 4) (same? 3 \"3\"))", code))?, "(True False False)");
+        // A pin cannot refer to a variable bound by its own pattern.
+        assert!(run("(def x 5) (match (, 1 5) ((, x (^ x)) \"same\") (_ \"diff\"))").is_err());
+        assert!(run("(def x 5) (match (, 1 1) ((, (^ x) x) \"same\") (_ \"diff\"))").is_err());
+        assert_eq!(run("(def x 5) (match (, 1 5) ((, y (^ x)) \"same\") (_ \"diff\"))")?, "\"same\"");
+        Ok(())
+    }
+
+    #[test]
+    fn list_pattern() -> Result<()> {
+        assert_eq!(run("(match [1 2 3] ([a b] 0) ([a b c] (+ a (+ b c))) (_ 1))")?, "6");
+        assert_eq!(run("(match [1 2 3] ([x & rest] rest) (_ Nil))")?, "(Cons 2 (Cons 3 Nil))");
+        Ok(())
+    }
+
     #[cfg(not(debug_assertions))] // Run only in Release
     #[test]
     fn ackermann() -> Result<()> {
@@ -576,14 +1174,14 @@ mod test {
          ((, _ 0) (ack (- m 1) 1))
          (_ (ack (- m 1) (ack m (- n 1)))))))", "TEST").proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
-        let (bytecode, symbols, _) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), true)?.compile(vec![])?;
+        let (bytecode, symbols, constructors, macros) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], false, lib(), true, vec![])?.compile(vec![])?;
 
-        let (ctx, sym_ref, saves) = VM::<256>::new(bytecode.clone(), vec![]).eval(vec![], vec![])?;
-        let (call_bytecode, ..) = Compiler::new(Parser::new(Lexer::new("(ack 3 6)", "TEST").proc_tokens()?, "TEST").parse()?, "TEST", bytecode, vec![], true, "".to_string(), true)?.compile(symbols)?;
+        let globals = VM::<256>::new(bytecode.clone()).eval(vec![], false)?;
+        let (call_bytecode, ..) = Compiler::new(Parser::new(Lexer::new("(ack 3 6)", "TEST").proc_tokens()?, "TEST").parse()?, "TEST", bytecode, constructors, true, lib(), true, macros)?.compile(symbols)?;
         let mut vals = (0..200).map(|_| {
-            let mut vm = VM::<16000>::new(call_bytecode.clone(), saves.clone());
+            let mut vm = VM::<16000>::new(call_bytecode.clone());
             let start = Instant::now();
-            vm.eval(sym_ref.clone(), ctx.clone())?;
+            vm.eval(globals.clone(), false)?;
             let elapsed = start.elapsed();
             Ok(elapsed.as_millis() as u32)
         }).collect::<Result<Vec<u32>>>()?;